@@ -12,8 +12,8 @@ type Oauth2Client = oauth2::basic::BasicClient<
 
 mod auth;
 mod index;
+mod upload;
 
-static COOKIE_NAME: &str = "SESSION";
 static CSRF_TOKEN: &str = "csrf_token";
 
 /// Combines the different states needed for the API to work
@@ -22,6 +22,12 @@ pub struct AxumState {
     pub state: std::sync::Arc<tokio::sync::RwLock<crate::State>>,
     pub auth_state: crate::auth::AuthState,
     pub client: Oauth2Client,
+    /// Used to trigger a reload of the packages, after their files changed
+    pub package_notifier: crate::background::Notifier,
+    /// The client used for all requests to upstream indexes
+    pub http_client: reqwest::Client,
+    /// The cache for files downloaded from upstream indexes, if enabled
+    pub file_cache: Option<crate::cache::FileCache>,
 }
 
 impl axum::extract::FromRef<AxumState> for crate::auth::AuthState {
@@ -73,6 +79,7 @@ pub fn api_router<S>(state: AxumState, session_store: S) -> axum::Router where S
         .route("/", axum::routing::get(landing_page))
         .merge(auth::auth_router())
         .merge(index::index_router(state.clone()))
+        .merge(upload::upload_router())
         .layer(tower_sessions::SessionManagerLayer::new(session_store).with_same_site(tower_sessions::cookie::SameSite::Lax).with_secure(true).with_http_only(true).with_path("/"))
         .with_state(state)
 }
@@ -35,11 +35,11 @@ async fn csrf_token_validation_workflow(
     session: &mut tower_sessions::Session,
 ) -> Result<(), ()> {
     // Extract the CSRF token from the session
-    let stored_csrf_token = session.get::<CsrfToken>(CSRF_TOKEN).await.map_err(|e| ())?.ok_or(())?.to_owned(); 
+    let stored_csrf_token = session.get::<CsrfToken>(CSRF_TOKEN).await.map_err(|_e| ())?.ok_or(())?.to_owned(); 
 
     // Cleanup the CSRF token session
-    session.remove::<CsrfToken>(CSRF_TOKEN).await.map_err(|e| ())?;
-    session.save().await.map_err(|e| ())?;
+    session.remove::<CsrfToken>(CSRF_TOKEN).await.map_err(|_e| ())?;
+    session.save().await.map_err(|_e| ())?;
 
     // Validate CSRF token is the same as the one in the auth request
     if *stored_csrf_token.secret() != auth_request.state {
@@ -49,13 +49,6 @@ async fn csrf_token_validation_workflow(
     Ok(())
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-struct GitlabUser {
-    username: String,
-    email: String,
-    name: String,
-}
-
 async fn login_authorized(
     axum::extract::Query(query): axum::extract::Query<AuthRequest>,
     axum::extract::State(oauth_client): axum::extract::State<Oauth2Client>,
@@ -72,15 +65,8 @@ async fn login_authorized(
         .await
         .unwrap();
 
-    // Fetch user data from discord
-    let client = reqwest::Client::new();
-    let user_data: GitlabUser = client
-        .get("https://gitlab.com/api/v4/user")
-        .bearer_auth(token.access_token().secret())
-        .send()
-        .await
-        .unwrap()
-        .json::<GitlabUser>()
+    // Fetch user data from gitlab
+    let user_data = crate::auth::gitlab_user(token.access_token().secret())
         .await
         .unwrap();
 
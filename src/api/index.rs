@@ -1,4 +1,6 @@
-use crate::auth::CustomAuth;
+use futures_util::StreamExt;
+
+use crate::{PackageName, UpstreamError, auth::CustomAuth, distribution::WheelFilter, version::AllowedVersions};
 
 use super::AxumState;
 
@@ -16,8 +18,41 @@ pub fn index_router(state: AxumState) -> axum::Router<AxumState> {
         ))
 }
 
+/// The packages the user has access to, along with the versions and wheels they are allowed to
+/// see
 #[derive(Debug, Clone)]
-struct UserPackages(pub Vec<String>);
+struct UserPackages {
+    packages: std::collections::BTreeMap<PackageName, AllowedVersions>,
+    wheel_filter: WheelFilter,
+}
+
+impl UserPackages {
+    fn contains(&self, package: &PackageName) -> bool {
+        self.packages.contains_key(package)
+    }
+
+    /// The files of the package which the user is allowed to see
+    fn visible_files(&self, package: &PackageName, files: &[crate::PackageFile]) -> Vec<crate::PackageFile> {
+        let versions = match self.packages.get(package) {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+
+        if versions.is_all() && self.wheel_filter.is_empty() {
+            return files.to_vec();
+        }
+
+        // Without a version we can't tell whether the file matches the specifiers, so it is hidden
+        files
+            .iter()
+            .filter(|f| match f.distribution() {
+                Some(d) => versions.contains(&d.version) && self.wheel_filter.allows(d),
+                None => versions.is_all(),
+            })
+            .cloned()
+            .collect()
+    }
+}
 
 async fn load_user_packages(
     authed: CustomAuth,
@@ -27,56 +62,320 @@ async fn load_user_packages(
 ) -> axum::response::Response {
     tracing::trace!(?authed, "Loading Packages for User");
 
-    let packages: Vec<String> = {
+    let packages = {
         let state = state.state.read().await;
 
         let all_packages = state.packages.keys();
 
         match authed {
-            CustomAuth::Customer { name } => match state.customer_packages.get(&name) {
-                Some(customer_packages) => all_packages
-                    .filter(|p| customer_packages.contains(*p))
-                    .cloned()
+            CustomAuth::Customer { name } => match state.customers.get(&name) {
+                Some(customer) => UserPackages {
+                    packages: all_packages
+                        .filter_map(|p| customer.packages.get(p).map(|v| (p.clone(), v.clone())))
+                        .collect(),
+                    wheel_filter: customer.wheel_filter.clone(),
+                },
+                None => UserPackages {
+                    packages: Default::default(),
+                    wheel_filter: WheelFilter::default(),
+                },
+            },
+            CustomAuth::Developer => UserPackages {
+                packages: all_packages
+                    .map(|p| (p.clone(), AllowedVersions::all()))
                     .collect(),
-                None => Vec::new(),
+                wheel_filter: WheelFilter::default(),
             },
-            CustomAuth::Developer => all_packages.cloned().collect(),
         }
     };
 
-    request.extensions_mut().insert(UserPackages(packages));
+    request.extensions_mut().insert(packages);
 
     next.run(request).await
 }
 
-#[tracing::instrument(skip(packages))]
+/// The content types of the simple API (PEP 691)
+const SIMPLE_JSON_V1: &str = "application/vnd.pypi.simple.v1+json";
+const SIMPLE_JSON_LATEST: &str = "application/vnd.pypi.simple.latest+json";
+const SIMPLE_HTML_V1: &str = "application/vnd.pypi.simple.v1+html";
+const SIMPLE_HTML_LATEST: &str = "application/vnd.pypi.simple.latest+html";
+
+/// The version of the simple API we serve
+const SIMPLE_API_VERSION: &str = "1.0";
+
+/// The format in which a simple API page should be returned
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SimpleFormat {
+    Html,
+    Json,
+}
+
+impl SimpleFormat {
+    /// Picks the format based on the `Accept` header of the request, as described in PEP 691.
+    ///
+    /// The supported media type with the highest quality wins, ties are resolved by the order in
+    /// the header. Requests without a (supported) `Accept` header get the HTML form.
+    fn negotiate(headers: &axum::http::HeaderMap) -> Self {
+        let mut best: Option<(Self, f32)> = None;
+
+        for value in headers.get_all(axum::http::header::ACCEPT) {
+            let value = match value.to_str() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            for entry in value.split(',') {
+                let mut parts = entry.split(';').map(|p| p.trim());
+                let media_type = parts.next().unwrap_or("");
+
+                let format = match media_type {
+                    SIMPLE_JSON_V1 | SIMPLE_JSON_LATEST => Self::Json,
+                    SIMPLE_HTML_V1 | SIMPLE_HTML_LATEST | "text/html" | "text/*" | "*/*" => Self::Html,
+                    _ => continue,
+                };
+
+                let quality = parts
+                    .filter_map(|p| p.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                if quality <= 0.0 {
+                    continue;
+                }
+
+                if best.map(|(_, q)| quality > q).unwrap_or(true) {
+                    best = Some((format, quality));
+                }
+            }
+        }
+
+        best.map(|(f, _)| f).unwrap_or(Self::Html)
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Html => "text/html",
+            Self::Json => SIMPLE_JSON_V1,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct JsonMeta {
+    #[serde(rename = "api-version")]
+    api_version: &'static str,
+}
+
+impl JsonMeta {
+    fn new() -> Self {
+        Self {
+            api_version: SIMPLE_API_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct JsonProjectList {
+    meta: JsonMeta,
+    projects: Vec<JsonProject>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct JsonProject {
+    name: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct JsonProjectDetail {
+    meta: JsonMeta,
+    name: String,
+    files: Vec<JsonFile>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct JsonFile {
+    filename: String,
+    url: String,
+    hashes: crate::FileHashes,
+    #[serde(rename = "requires-python", skip_serializing_if = "Option::is_none")]
+    requires_python: Option<String>,
+    yanked: JsonFlag<String>,
+    #[serde(rename = "core-metadata")]
+    core_metadata: JsonFlag<crate::FileHashes>,
+    #[serde(rename = "dist-info-metadata")]
+    dist_info_metadata: JsonFlag<crate::FileHashes>,
+    #[serde(rename = "gpg-sig", skip_serializing_if = "Option::is_none")]
+    gpg_sig: Option<bool>,
+}
+
+/// A JSON value that is either a plain boolean or carries additional information, like the
+/// `yanked` reason or the `core-metadata` hashes
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+enum JsonFlag<T> {
+    Flag(bool),
+    Value(T),
+}
+
+impl JsonFile {
+    fn new(file: &crate::PackageFile) -> Self {
+        let attributes = file.attributes();
+
+        let yanked = match &attributes.yanked {
+            Some(reason) if !reason.is_empty() => JsonFlag::Value(reason.clone()),
+            Some(_) => JsonFlag::Flag(true),
+            None => JsonFlag::Flag(false),
+        };
+
+        let core_metadata = || match &attributes.core_metadata {
+            Some(hashes) if !hashes.is_empty() => JsonFlag::Value(hashes.clone()),
+            Some(_) => JsonFlag::Flag(true),
+            None => JsonFlag::Flag(false),
+        };
+
+        Self {
+            filename: file.name().to_string(),
+            url: file.name().to_string(),
+            hashes: file.hashes().clone(),
+            requires_python: attributes.requires_python.clone(),
+            yanked,
+            core_metadata: core_metadata(),
+            dist_info_metadata: core_metadata(),
+            gpg_sig: attributes.gpg_sig,
+        }
+    }
+}
+
+/// Escapes a value for use in HTML text or a quoted attribute
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders the link to a file for the HTML form of the project page, including all the `data-*`
+/// attributes we know about
+fn html_file_link(file: &crate::PackageFile) -> String {
+    let attributes = file.attributes();
+
+    let href = match file.hash_fragment() {
+        Some(fragment) => format!("{}#{}", file.name(), fragment),
+        None => file.name().to_string(),
+    };
+
+    let mut link = format!("<a href=\"{}\"", escape_html(&href));
+
+    if let Some(requires_python) = &attributes.requires_python {
+        link.push_str(&format!(" data-requires-python=\"{}\"", escape_html(requires_python)));
+    }
+    if let Some(reason) = &attributes.yanked {
+        link.push_str(&format!(" data-yanked=\"{}\"", escape_html(reason)));
+    }
+    if let Some(hashes) = &attributes.core_metadata {
+        let value = crate::preferred_hash(hashes)
+            .map(|(name, digest)| format!("{}={}", name, digest))
+            .unwrap_or_else(|| "true".to_string());
+        link.push_str(&format!(
+            " data-core-metadata=\"{0}\" data-dist-info-metadata=\"{0}\"",
+            escape_html(&value)
+        ));
+    }
+    if let Some(gpg_sig) = attributes.gpg_sig {
+        link.push_str(&format!(" data-gpg-sig=\"{}\"", gpg_sig));
+    }
+
+    format!("{}>{}</a><br/>", link, escape_html(file.name()))
+}
+
+/// Builds the response for a simple API page in the given format
+fn simple_response(format: SimpleFormat, content: String) -> axum::response::Response<String> {
+    axum::response::Response::builder()
+        .status(200)
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .body(content)
+        .unwrap()
+}
+
+#[tracing::instrument(skip(packages, headers))]
 async fn package_index(
+    headers: axum::http::HeaderMap,
     axum::extract::Extension(packages): axum::extract::Extension<UserPackages>,
-) -> axum::response::Html<String> {
-    tracing::debug!("Simple Index");
+) -> axum::response::Response<String> {
+    let format = SimpleFormat::negotiate(&headers);
+    tracing::debug!(?format, "Simple Index");
+
+    let response = match format {
+        SimpleFormat::Html => format!(
+            "<html><body>{}</body></html>",
+            packages
+                .packages
+                .keys()
+                .map(|p| format!("<a href=\"{}/\">{}</a><br/>", p, p))
+                .collect::<String>()
+        ),
+        SimpleFormat::Json => {
+            let content = JsonProjectList {
+                meta: JsonMeta::new(),
+                projects: packages
+                    .packages
+                    .keys()
+                    .map(|p| JsonProject { name: p.to_string() })
+                    .collect(),
+            };
 
-    let response = format!(
-        "<html><body>{}</body></html>",
-        packages
-            .0
-            .iter()
-            .map(|p| format!("<a href=\"{}/\">{}</a><br/>", p, p))
-            .collect::<String>()
-    );
+            serde_json::to_string(&content).unwrap()
+        }
+    };
 
-    axum::response::Html(response)
+    simple_response(format, response)
 }
 
-#[tracing::instrument(skip(packages, state))]
+/// PEP 503 asks for non normalized project URLs to be redirected to the normalized one, `None` if
+/// the name is already normalized
+fn normalized_redirect(package: &str) -> Option<axum::response::Response<String>> {
+    if PackageName::is_normalized(package) {
+        return None;
+    }
+
+    let normalized = PackageName::new(package);
+    tracing::debug!(%normalized, "Redirecting to normalized package name");
+
+    Some(
+        axum::response::Response::builder()
+            .status(301)
+            .header("Location", format!("../{}/", normalized))
+            .body("".into())
+            .unwrap(),
+    )
+}
+
+#[tracing::instrument(skip(packages, state, headers))]
 async fn package_files(
+    headers: axum::http::HeaderMap,
     axum::extract::Path(package): axum::extract::Path<String>,
     axum::extract::Extension(packages): axum::extract::Extension<UserPackages>,
     axum::extract::State(state): axum::extract::State<AxumState>,
 ) -> axum::response::Response<String> {
-    tracing::debug!("Files for package");
+    let format = SimpleFormat::negotiate(&headers);
+    tracing::debug!(?format, "Files for package");
+
+    if let Some(redirect) = normalized_redirect(&package) {
+        return redirect;
+    }
+    let package = PackageName::new(&package);
 
     // Check if the user has the package configured
-    if !packages.0.iter().any(|p| p == &package) {
+    if !packages.contains(&package) {
         tracing::error!("Unknown file request for user");
 
         return axum::response::Response::builder()
@@ -88,9 +387,9 @@ async fn package_files(
     let state = state.state.read().await;
 
     let files = match state.packages.get(&package) {
-        Some(package) => {
+        Some(v) => {
             tracing::debug!("Package found");
-            package.files.clone()
+            packages.visible_files(&package, &v.files)
         }
         None => {
             tracing::error!("Unknown Package");
@@ -98,25 +397,29 @@ async fn package_files(
         }
     };
 
-    let response_content = format!(
-        "<html><body>{}</body></html>",
-        files
-            .into_iter()
-            .map(|f| {
-                let name = match f {
-                    crate::PackageFile::RemotePackage { name, .. } => name,
-                    crate::PackageFile::FilePackage { name, .. } => name,
-                };
-                format!("<a href=\"{}\">{}</a><br/>", name, name)
-            })
-            .collect::<String>()
-    );
+    let response_content = match format {
+        SimpleFormat::Html => format!(
+            "<html><body>{}</body></html>",
+            files
+                .iter()
+                .map(html_file_link)
+                .collect::<String>()
+        ),
+        SimpleFormat::Json => {
+            let content = JsonProjectDetail {
+                meta: JsonMeta::new(),
+                name: package.to_string(),
+                files: files
+                    .iter()
+                    .map(JsonFile::new)
+                    .collect(),
+            };
 
-    axum::response::Response::builder()
-        .status(200)
-        .header("Content-Type", "text/html")
-        .body(response_content)
-        .unwrap()
+            serde_json::to_string(&content).unwrap()
+        }
+    };
+
+    simple_response(format, response_content)
 }
 
 #[tracing::instrument(skip(packages, state))]
@@ -127,7 +430,9 @@ async fn download_file(
 ) -> axum::http::Response<axum::body::Body> {
     tracing::debug!(?package, ?filename, "Download file for package");
 
-    if !packages.0.iter().any(|p| p == &package) {
+    let package = PackageName::new(&package);
+
+    if !packages.contains(&package) {
         tracing::error!("Unknown file request for user");
 
         return axum::response::Response::builder()
@@ -136,12 +441,22 @@ async fn download_file(
             .unwrap();
     }
 
-    let state = state.state.read().await;
+    let files = match state.state.read().await.packages.get(&package) {
+        Some(v) => packages.visible_files(&package, &v.files),
+        None => {
+            tracing::error!("Unknown file request for user");
+
+            return axum::response::Response::builder()
+                .status(404)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+    };
 
-    let test = match state.packages.get(&package) {
+    let (file, kind) = match find_file(&files, &filename) {
         Some(v) => v,
         None => {
-            tracing::error!("Unknown file request for user");
+            tracing::error!("Unknown file");
 
             return axum::response::Response::builder()
                 .status(404)
@@ -150,43 +465,230 @@ async fn download_file(
         }
     };
 
-    let file = test.files.iter().find(|f| match f {
-        crate::PackageFile::RemotePackage { name, .. } => name == &filename,
-        crate::PackageFile::FilePackage { name, .. } => name == &filename,
-    });
+    match (file, kind) {
+        (crate::PackageFile::RemotePackage { name, url, auth, hashes, .. }, kind) => {
+            tracing::trace!(?kind, "Found Remote Package");
 
-    match file {
-        Some(crate::PackageFile::RemotePackage { url, auth, .. }) => {
-            tracing::trace!("Found Remote Package");
+            // Only the distributions themselves are worth caching
+            let cache = match kind {
+                RequestedFile::Distribution => state.file_cache.as_ref(),
+                RequestedFile::Metadata | RequestedFile::Signature => None,
+            };
 
-            let client = reqwest::Client::new();
+            let cached = match cache {
+                Some(cache) => cache.get(&package, name, hashes).await,
+                None => None,
+            };
+            if let Some(file) = cached {
+                tracing::trace!("Serving Remote Package from cache");
+
+                let mut response = axum::response::Response::builder().status(200);
+                if let Ok(metadata) = file.metadata().await {
+                    response = response.header("Content-Length", metadata.len());
+                }
+
+                return response
+                    .body(axum::body::Body::from_stream(
+                        tokio_util::io::ReaderStream::new(file),
+                    ))
+                    .unwrap();
+            }
+
+            let mut url = url.clone();
+            url.set_path(&format!("{}{}", url.path(), kind.suffix()));
+
+            let response = auth.send(url, |url| state.http_client.get(url)).await;
+
+            let response = match response.and_then(|r| r.error_for_status().map_err(UpstreamError::Request)) {
+                Ok(r) => r,
+                Err(e) => {
+                    tracing::error!(?e, "Downloading Remote Package");
+
+                    return axum::response::Response::builder()
+                        .status(502)
+                        .body(axum::body::Body::empty())
+                        .unwrap();
+                }
+            };
 
-            let req = client.get(url.clone());
-            let req = match auth {
-                crate::RemotePackageAuth::Unauthorized => req,
+            let writer = match cache {
+                Some(cache) => match cache.writer(&package, name, hashes).await {
+                    Ok(w) => w,
+                    Err(e) => {
+                        tracing::error!(?e, "Creating cache file");
+                        None
+                    }
+                },
+                None => None,
             };
 
-            let response = req.send().await.unwrap();
-            axum::response::Response::builder()
-                .status(200)
-                .body(axum::body::Body::from_stream(response.bytes_stream()))
-                .unwrap()
+            let mut builder = axum::response::Response::builder().status(200);
+            if let Some(length) = response.content_length() {
+                builder = builder.header("Content-Length", length);
+            }
+
+            let body = match writer {
+                Some(writer) => axum::body::Body::from_stream(stream_into_cache(response, writer)),
+                None => axum::body::Body::from_stream(response.bytes_stream()),
+            };
+
+            builder.body(body).unwrap()
         }
-        Some(crate::PackageFile::FilePackage { path, .. }) => {
+        (crate::PackageFile::FilePackage { path, .. }, RequestedFile::Distribution) => {
             tracing::trace!("Found FIle Package");
 
-            let file = tokio::fs::File::open(path).await.unwrap();
+            // The file may have been removed since the folder was last read
+            let file = match tokio::fs::File::open(path).await {
+                Ok(f) => f,
+                Err(e) => {
+                    tracing::error!(?e, ?path, "Opening File Package");
+
+                    let status = match e.kind() {
+                        std::io::ErrorKind::NotFound => 404,
+                        _ => 500,
+                    };
+                    return axum::response::Response::builder()
+                        .status(status)
+                        .body(axum::body::Body::empty())
+                        .unwrap();
+                }
+            };
 
-            axum::response::Response::builder()
-                .status(200)
+            let mut response = axum::response::Response::builder().status(200);
+            if let Ok(metadata) = file.metadata().await {
+                response = response.header("Content-Length", metadata.len());
+            }
+
+            response
                 .body(axum::body::Body::from_stream(
                     tokio_util::io::ReaderStream::new(file),
                 ))
                 .unwrap()
         }
-        None => axum::response::Response::builder()
-            .status(404)
-            .body(axum::body::Body::empty())
-            .unwrap(),
+        (crate::PackageFile::FilePackage { metadata: Some(metadata), .. }, RequestedFile::Metadata) => {
+            tracing::trace!("Found File Package Metadata");
+
+            axum::response::Response::builder()
+                .status(200)
+                .body(axum::body::Body::from(metadata.to_vec()))
+                .unwrap()
+        }
+        (crate::PackageFile::FilePackage { .. }, kind) => {
+            tracing::error!(?kind, "Not available for local file");
+
+            axum::response::Response::builder()
+                .status(404)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+/// Streams the upstream response to the client, while also writing it into the cache.
+///
+/// The download continues even if the client disconnects, so the file still ends up in the cache
+fn stream_into_cache(
+    response: reqwest::Response,
+    writer: crate::cache::CacheWriter,
+) -> impl futures_util::Stream<Item = Result<bytes::Bytes, std::io::Error>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let mut upstream = response.bytes_stream();
+        let mut writer = Some(writer);
+
+        while let Some(chunk) = upstream.next().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(e) => {
+                    tracing::error!(?e, "Streaming Remote Package");
+                    let _ = tx.send(Err(std::io::Error::other(e))).await;
+                    return;
+                }
+            };
+
+            let written = match writer.as_mut() {
+                Some(w) => w.write(&chunk).await,
+                None => Ok(()),
+            };
+            if let Err(e) = written {
+                tracing::error!(?e, "Writing to cache file");
+                writer = None;
+            }
+
+            // Once the client is gone, we only keep going to fill the cache
+            if tx.send(Ok(chunk)).await.is_err() && writer.is_none() {
+                return;
+            }
+        }
+
+        let finished = match writer {
+            Some(writer) => writer.finish().await,
+            None => Ok(()),
+        };
+        if let Err(e) = finished {
+            tracing::error!(?e, "Storing file in cache");
+        }
+    });
+
+    futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+}
+
+/// What is being requested for a package file
+#[derive(Debug, Clone, Copy)]
+enum RequestedFile {
+    /// The distribution file itself
+    Distribution,
+    /// The core metadata of the file (`{file}.metadata`)
+    Metadata,
+    /// The GPG signature of the file (`{file}.asc`)
+    Signature,
+}
+
+impl RequestedFile {
+    fn suffix(&self) -> &'static str {
+        match self {
+            Self::Distribution => "",
+            Self::Metadata => ".metadata",
+            Self::Signature => ".asc",
+        }
+    }
+}
+
+/// Finds the file matching the requested filename, which could also be one of the files provided
+/// alongside a distribution
+fn find_file<'f>(
+    files: &'f [crate::PackageFile],
+    filename: &str,
+) -> Option<(&'f crate::PackageFile, RequestedFile)> {
+    if let Some(file) = files.iter().find(|f| f.name() == filename) {
+        return Some((file, RequestedFile::Distribution));
+    }
+
+    let sidecar = |kind: RequestedFile, available: fn(&crate::PackageFile) -> bool| {
+        let name = filename.strip_suffix(kind.suffix())?;
+        files
+            .iter()
+            .find(|f| f.name() == name && available(f))
+            .map(|f| (f, kind))
+    };
+
+    sidecar(RequestedFile::Metadata, |f| f.attributes().core_metadata.is_some())
+        .or_else(|| sidecar(RequestedFile::Signature, |f| f.attributes().gpg_sig == Some(true)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_to_the_normalized_name() {
+        let redirect = normalized_redirect("Scikit_Learn").unwrap();
+
+        assert_eq!(redirect.status(), 301);
+        assert_eq!(redirect.headers()["Location"], "../scikit-learn/");
+        assert!(normalized_redirect("scikit-learn").is_none());
     }
 }
@@ -0,0 +1,354 @@
+//! The legacy upload API, as used by `twine upload` and `uv publish`
+
+use sha2::Digest;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    PackageName, PackageSrc, auth::{CustomAuth, UploadAuth}, distribution::DistributionFilename,
+    version::Version,
+};
+
+use super::AxumState;
+
+pub fn upload_router() -> axum::Router<AxumState> {
+    axum::Router::new()
+        .route("/legacy/", axum::routing::post(upload_file))
+        // Distributions are easily larger than the default body limit
+        .layer(axum::extract::DefaultBodyLimit::disable())
+}
+
+#[derive(Debug)]
+enum UploadError {
+    NotDeveloper,
+    Multipart(axum::extract::multipart::MultipartError),
+    UnsupportedAction(String),
+    MissingField(&'static str),
+    InvalidFilename(String),
+    NameMismatch {
+        expected: PackageName,
+        filename: PackageName,
+    },
+    VersionMismatch {
+        expected: String,
+        filename: String,
+    },
+    DigestMismatch,
+    UnknownPackage(PackageName),
+    NoFolder(PackageName),
+    AlreadyExists(String),
+    Io(std::io::Error),
+}
+
+impl axum::response::IntoResponse for UploadError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, message) = match &self {
+            Self::NotDeveloper => (403, "Only developers are allowed to upload".to_string()),
+            Self::Multipart(e) => (400, format!("Invalid multipart body: {}", e)),
+            Self::UnsupportedAction(action) => (400, format!("Unsupported action '{}'", action)),
+            Self::MissingField(field) => (400, format!("Missing field '{}'", field)),
+            Self::InvalidFilename(filename) => (400, format!("Invalid filename '{}'", filename)),
+            Self::NameMismatch { expected, filename } => (
+                400,
+                format!("Filename is for package '{}' instead of '{}'", filename, expected),
+            ),
+            Self::VersionMismatch { expected, filename } => (
+                400,
+                format!("Filename is for version '{}' instead of '{}'", filename, expected),
+            ),
+            Self::DigestMismatch => (400, "The sha256 digest does not match".to_string()),
+            Self::UnknownPackage(name) => (404, format!("Unknown package '{}'", name)),
+            Self::NoFolder(name) => (
+                400,
+                format!("Package '{}' is not configured with a folder", name),
+            ),
+            Self::AlreadyExists(filename) => (409, format!("File '{}' already exists", filename)),
+            Self::Io(e) => (500, format!("Storing the file: {}", e)),
+        };
+
+        if status == 500 {
+            tracing::error!(error = ?self, "Uploading file");
+        } else {
+            tracing::warn!(error = ?self, "Rejected upload");
+        }
+
+        axum::response::Response::builder()
+            .status(status)
+            .body(axum::body::Body::from(message))
+            .unwrap()
+    }
+}
+
+/// The uploaded content, stored in a temporary file next to its final location
+struct UploadedFile {
+    filename: String,
+    distribution: DistributionFilename,
+    temp_file: TempFile,
+    target_path: std::path::PathBuf,
+    sha256: String,
+}
+
+/// A temporary file, which is removed once dropped. This includes the request being aborted, for
+/// example because the client disconnected
+struct TempFile {
+    path: std::path::PathBuf,
+}
+
+impl TempFile {
+    /// Creates a new file in the folder, with a unique name so neither concurrent uploads nor
+    /// files left behind by a crash get in the way. The leading `.` and the suffix make sure the
+    /// package loader ignores the partial file
+    async fn create(folder: &std::path::Path, filename: &str) -> std::io::Result<(Self, tokio::fs::File)> {
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        loop {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let path = folder.join(format!(".{}.{}-{}-{}.upload", filename, std::process::id(), nanos, count));
+
+            match tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => return Ok((Self { path }, file)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // Remove the file without blocking the runtime if there is one
+        let path = self.path.clone();
+        let remove = move || {
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::error!(?e, ?path, "Removing temporary upload file");
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(remove);
+            }
+            Err(_) => remove(),
+        };
+    }
+}
+
+#[tracing::instrument(skip(state, multipart))]
+async fn upload_file(
+    UploadAuth(auth): UploadAuth,
+    axum::extract::State(state): axum::extract::State<AxumState>,
+    multipart: axum::extract::Multipart,
+) -> Result<&'static str, UploadError> {
+    if !matches!(auth, CustomAuth::Developer) {
+        return Err(UploadError::NotDeveloper);
+    }
+
+    let mut action = None;
+    let mut name = None;
+    let mut version = None;
+    let mut sha256_digest = None;
+
+    let uploaded = read_fields(&state, multipart, |field_name, value| match field_name {
+        ":action" => action = Some(value),
+        "name" => name = Some(value),
+        "version" => version = Some(value),
+        "sha256_digest" => sha256_digest = Some(value),
+        _ => {}
+    })
+    .await?;
+
+    let uploaded = match uploaded {
+        Some(u) => u,
+        None => return Err(UploadError::MissingField("content")),
+    };
+
+    let validated = (|| {
+        match action.as_deref() {
+            Some("file_upload") => {}
+            Some(other) => return Err(UploadError::UnsupportedAction(other.to_string())),
+            None => return Err(UploadError::MissingField(":action")),
+        };
+
+        let expected_name = PackageName::new(name.as_deref().ok_or(UploadError::MissingField("name"))?);
+        if expected_name != uploaded.distribution.name {
+            return Err(UploadError::NameMismatch {
+                expected: expected_name,
+                filename: uploaded.distribution.name.clone(),
+            });
+        }
+
+        let expected_version = version.ok_or(UploadError::MissingField("version"))?;
+        if Version::parse(&expected_version).ok().as_ref() != Some(&uploaded.distribution.version) {
+            return Err(UploadError::VersionMismatch {
+                expected: expected_version,
+                filename: uploaded.distribution.version.to_string(),
+            });
+        }
+
+        if sha256_digest.is_some_and(|digest| !digest.eq_ignore_ascii_case(&uploaded.sha256)) {
+            return Err(UploadError::DigestMismatch);
+        }
+
+        Ok(())
+    })();
+
+    // Hard linking fails if the target already exists, so we never overwrite an existing file,
+    // even if it was created while the upload was in progress
+    let result = match validated {
+        Ok(()) => match tokio::fs::hard_link(&uploaded.temp_file.path, &uploaded.target_path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(UploadError::AlreadyExists(uploaded.filename.clone()))
+            }
+            Err(e) => Err(UploadError::Io(e)),
+        },
+        Err(e) => Err(e),
+    };
+
+    // Only the hard link is kept
+    drop(uploaded.temp_file);
+    result?;
+
+    tracing::info!(filename = ?uploaded.filename, "Uploaded file");
+
+    if let Err(e) = state.package_notifier.notify() {
+        tracing::error!(?e, "Could not notify package reload");
+    }
+
+    Ok("OK")
+}
+
+/// Reads all the fields of the upload, streaming the `content` to a temporary file in the
+/// package's folder and passing all the other fields to `on_field`
+async fn read_fields(
+    state: &AxumState,
+    mut multipart: axum::extract::Multipart,
+    mut on_field: impl FnMut(&str, String),
+) -> Result<Option<UploadedFile>, UploadError> {
+    let mut uploaded: Option<UploadedFile> = None;
+
+    let result: Result<(), UploadError> = async {
+        while let Some(field) = multipart.next_field().await.map_err(UploadError::Multipart)? {
+            let field_name = field.name().unwrap_or_default().to_string();
+            if field_name != "content" {
+                let value = field.text().await.map_err(UploadError::Multipart)?;
+                on_field(&field_name, value);
+                continue;
+            }
+
+            if uploaded.is_some() {
+                tracing::warn!("Ignoring additional content field");
+                continue;
+            }
+
+            uploaded = Some(receive_content(state, field).await?);
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // On errors, dropping the upload removes its temporary file
+    result.map(|()| uploaded)
+}
+
+async fn receive_content(
+    state: &AxumState,
+    mut field: axum::extract::multipart::Field<'_>,
+) -> Result<UploadedFile, UploadError> {
+    let filename = field
+        .file_name()
+        .ok_or(UploadError::MissingField("content"))?
+        .to_string();
+
+    // The filename ends up being used as a path, so only accept plain names
+    if filename.starts_with('.') || filename.contains(['/', '\\']) {
+        return Err(UploadError::InvalidFilename(filename));
+    }
+
+    let distribution = DistributionFilename::parse(&filename)
+        .ok_or_else(|| UploadError::InvalidFilename(filename.clone()))?;
+
+    let folder = {
+        let state = state.state.read().await;
+        let package = state
+            .packages
+            .get(&distribution.name)
+            .ok_or_else(|| UploadError::UnknownPackage(distribution.name.clone()))?;
+
+        // Uploads go into the folder with the highest priority
+        package
+            .sources
+            .iter()
+            .find_map(|src| match src {
+                PackageSrc::Folder { path } => Some(path.clone()),
+                PackageSrc::Index { .. } => None,
+            })
+            .ok_or_else(|| UploadError::NoFolder(distribution.name.clone()))?
+    };
+
+    let target_path = folder.join(&filename);
+    if tokio::fs::try_exists(&target_path).await.map_err(UploadError::Io)? {
+        return Err(UploadError::AlreadyExists(filename));
+    }
+
+    let (temp_file, mut file) = TempFile::create(&folder, &filename)
+        .await
+        .map_err(UploadError::Io)?;
+
+    let mut hasher = sha2::Sha256::new();
+    let written: Result<(), UploadError> = async {
+        while let Some(chunk) = field.chunk().await.map_err(UploadError::Multipart)? {
+            hasher.update(&chunk);
+            file.write_all(&chunk).await.map_err(UploadError::Io)?;
+        }
+        file.flush().await.map_err(UploadError::Io)?;
+        file.sync_all().await.map_err(UploadError::Io)
+    }
+    .await;
+
+    written?;
+
+    Ok(UploadedFile {
+        filename,
+        distribution,
+        temp_file,
+        target_path,
+        sha256: format!("{:x}", hasher.finalize()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_files_are_unique_and_removed() {
+        let dir = std::env::temp_dir().join(format!("cypi-upload-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (first, second) = runtime.block_on(async {
+            let (first, _) = TempFile::create(&dir, "numpy-1.0.tar.gz").await.unwrap();
+            let (second, _) = TempFile::create(&dir, "numpy-1.0.tar.gz").await.unwrap();
+            (first, second)
+        });
+        assert_ne!(first.path, second.path);
+
+        let path = first.path.clone();
+        assert!(path.exists());
+        drop(first);
+        assert!(!path.exists());
+
+        drop(second);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -1,25 +1,64 @@
 use std::collections::HashMap;
 
-use tower_sessions::SessionStore;
-use axum::{extract::FromRef, response::sse};
+use axum::extract::FromRef;
 use axum_extra::headers::HeaderMapExt;
 use tracing::Instrument;
 
+pub mod credentials;
+pub mod token;
+
+/// The GitLab API endpoint returning the user a token belongs to
+static GITLAB_USER_URL: &str = "https://gitlab.com/api/v4/user";
+
+/// The basic auth username used by developers to authenticate with a GitLab token instead of a
+/// session when uploading, which is needed for tools like `twine` or `uv publish`
+pub static GITLAB_TOKEN_USERNAME: &str = "__token__";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct GitlabUser {
+    pub id: u64,
+    pub username: String,
+    pub email: String,
+    pub name: String,
+}
+
+/// Loads the GitLab user the given (OAuth or personal access) token belongs to
+pub async fn gitlab_user(token: &str) -> Result<GitlabUser, reqwest::Error> {
+    let client = reqwest::Client::new();
+    client
+        .get(GITLAB_USER_URL)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<GitlabUser>()
+        .await
+}
+
 #[derive(Debug)]
 pub enum CustomAuth {
     Customer { name: String },
     Developer,
 }
 
+/// Like [`CustomAuth`], but developers can also authenticate with the [`GITLAB_TOKEN_USERNAME`]
+/// and a GitLab access token. Only used for uploads, so browsing the index never calls GitLab
+#[derive(Debug)]
+pub struct UploadAuth(pub CustomAuth);
+
 #[derive(Debug, Clone)]
 pub struct AuthState {
-    pub customers: std::sync::Arc<tokio::sync::RwLock<HashMap<String, String>>>,
+    pub customers: std::sync::Arc<tokio::sync::RwLock<HashMap<String, credentials::Credential>>>,
+    pub gitlab_tokens: token::GitlabTokenAuth,
+    pub verifier: credentials::CredentialVerifier,
 }
 
 impl AuthState {
-    pub fn new() -> Self {
+    pub fn new(gitlab_tokens: token::GitlabTokenAuth) -> Self {
         Self {
-            customers: std::sync::Arc::new(tokio::sync::RwLock::new(HashMap::new()))
+            customers: std::sync::Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+            gitlab_tokens,
+            verifier: credentials::CredentialVerifier::new(),
         }
     }
 }
@@ -42,17 +81,30 @@ where
 
             if let Some(h) = header.typed_get::<axum_extra::headers::Authorization<axum_extra::headers::authorization::Basic>>() {
                 let auth: AuthState = AuthState::from_ref(state);
-                let customers = auth.customers.read().await;
+                let (credential, hashed) = {
+                    let customers = auth.customers.read().await;
+                    let credential = customers.get(h.username()).cloned();
+                    let hashed = credential.is_none() && customers.values().any(|c| matches!(c, credentials::Credential::Argon2(_)));
+                    (credential, hashed)
+                };
 
-                if let Some(customer_password) = customers.get(h.username()) {
-                    if customer_password == h.password() {
-                        return Ok(Self::Customer { name: h.username().to_string() });
+                let valid = match credential {
+                    Some(credential) => auth.verifier.verify(h.username(), h.password(), &credential).await,
+                    // Only hashes take a noticeable time to verify
+                    None if hashed => {
+                        auth.verifier.verify_unknown(h.password()).await;
+                        false
                     }
+                    None => false,
+                };
+                if valid {
+                    return Ok(Self::Customer { name: h.username().to_string() });
                 }
             }
 
             if let Ok(session) = tower_sessions::Session::from_request_parts(parts, state).await {
-                if session.get::<String>("gitlab-username").await.map(|v| v.is_some()).unwrap_or(false) {
+                let logged_in = session.get::<String>("gitlab-username").await.map(|v| v.is_some()).unwrap_or(false);
+                if logged_in {
                     return Ok(Self::Developer);
                 }
             }
@@ -65,3 +117,41 @@ where
         .instrument(tracing::trace_span!("CustomAuth-Extractor"))
     }
 }
+
+impl<S> axum::extract::FromRequestParts<S> for UploadAuth
+where
+    AuthState: axum::extract::FromRef<S>,
+    S: Sync + Send,
+{
+    type Rejection = axum::response::Response;
+
+    fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        async move {
+            let basic = parts.headers.typed_get::<axum_extra::headers::Authorization<axum_extra::headers::authorization::Basic>>();
+
+            match basic {
+                Some(h) if h.username() == GITLAB_TOKEN_USERNAME => {
+                    let auth: AuthState = AuthState::from_ref(state);
+                    match auth.gitlab_tokens.authenticate(h.password()).await {
+                        Ok(username) => {
+                            tracing::debug!(?username, "Authenticated developer using token");
+                            Ok(Self(CustomAuth::Developer))
+                        }
+                        Err(e) => {
+                            tracing::warn!(?e, "Authenticating developer using token");
+                            Err(axum::response::Response::builder()
+                                .status(401)
+                                .body(axum::body::Body::empty())
+                                .unwrap())
+                        }
+                    }
+                }
+                _ => CustomAuth::from_request_parts(parts, state).await.map(Self),
+            }
+        }
+        .instrument(tracing::trace_span!("UploadAuth-Extractor"))
+    }
+}
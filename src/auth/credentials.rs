@@ -0,0 +1,358 @@
+//! The sources of the credentials customers authenticate with
+
+use std::collections::HashMap;
+
+use argon2::{PasswordHasher, PasswordVerifier};
+use sha2::Digest;
+use subtle::ConstantTimeEq;
+
+use crate::{config, vault};
+
+/// The credential of a single customer
+#[derive(Clone, PartialEq)]
+pub enum Credential {
+    Password(String),
+    /// The Argon2 hash of the password as PHC string, like `$argon2id$v=19$m=19456,t=2,p=1$...`
+    Argon2(String),
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Password(_) => f.debug_tuple("Password").finish_non_exhaustive(),
+            Self::Argon2(_) => f.debug_tuple("Argon2").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl Credential {
+    /// Parses a hashed credential, which has to be an Argon2 PHC string including its salt
+    pub fn parse_hashed(value: &str) -> Option<Self> {
+        let hash = argon2::PasswordHash::new(value).ok()?;
+        let is_argon2 = [argon2::ARGON2ID_IDENT, argon2::ARGON2I_IDENT, argon2::ARGON2D_IDENT]
+            .contains(&hash.algorithm);
+        if !is_argon2 || hash.salt.is_none() || hash.hash.is_none() {
+            return None;
+        }
+
+        Some(Self::Argon2(value.to_string()))
+    }
+
+    /// Whether the password matches the credential. Hashed credentials are deliberately slow to
+    /// verify, so this should not be called on the async runtime
+    pub fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Password(expected) => expected.as_bytes().ct_eq(password.as_bytes()).into(),
+            // Compares the hashes in constant time as well
+            Self::Argon2(hash) => argon2::Argon2::default()
+                .verify_password(password.as_bytes(), hash.as_str())
+                .is_ok(),
+        }
+    }
+}
+
+/// How long a successful verification of a password hash is remembered, so not every request of a
+/// customer has to verify the hash again
+const VERIFIED_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// A hash of a random password, verified for unknown usernames so they take as long to reject as
+/// known ones
+static UNKNOWN_USER_HASH: std::sync::LazyLock<Credential> = std::sync::LazyLock::new(|| {
+    let hash = argon2::Argon2::default()
+        .hash_password(b"cypi-unknown-user")
+        .expect("Hashing with the default parameters works");
+    Credential::Argon2(hash.to_string())
+});
+
+/// The SHA-256 digests of the usernames and passwords of successful verifications, mapped to the
+/// credential they were verified against and when they expire. Only the digests are kept, so the
+/// passwords themselves are not stored
+type VerifiedCache = HashMap<[u8; 32], (Credential, std::time::Instant)>;
+
+/// Verifies the passwords of customers, limiting how many password hashes are verified at once
+/// (as every verification takes a lot of memory and CPU) and remembering successful verifications
+/// for a while
+#[derive(Clone)]
+pub struct CredentialVerifier {
+    cache: std::sync::Arc<std::sync::Mutex<VerifiedCache>>,
+    permits: std::sync::Arc<tokio::sync::Semaphore>,
+}
+
+impl std::fmt::Debug for CredentialVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CredentialVerifier").finish_non_exhaustive()
+    }
+}
+
+impl Default for CredentialVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CredentialVerifier {
+    pub fn new() -> Self {
+        let permits = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        Self {
+            cache: Default::default(),
+            permits: std::sync::Arc::new(tokio::sync::Semaphore::new(permits)),
+        }
+    }
+
+    /// Whether the password matches the credential of the customer
+    pub async fn verify(&self, username: &str, password: &str, credential: &Credential) -> bool {
+        if let Credential::Password(_) = credential {
+            return credential.verify(password);
+        }
+
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(username.len().to_le_bytes());
+        hasher.update(username.as_bytes());
+        hasher.update(password.as_bytes());
+        let digest: [u8; 32] = hasher.finalize().into();
+
+        let now = std::time::Instant::now();
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&digest)
+            .is_some_and(|(verified, expires)| verified == credential && *expires > now);
+        if cached {
+            return true;
+        }
+
+        let valid = {
+            let credential = credential.clone();
+            let password = password.to_string();
+            self.verify_blocking(move || credential.verify(&password)).await
+        };
+        if valid {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (_, expires)| *expires > now);
+            cache.insert(digest, (credential.clone(), now + VERIFIED_TTL));
+        }
+
+        valid
+    }
+
+    /// Verifies the password against a made up hash, so rejecting an unknown username takes as
+    /// long as rejecting a wrong password
+    pub async fn verify_unknown(&self, password: &str) {
+        let password = password.to_string();
+        self.verify_blocking(move || UNKNOWN_USER_HASH.verify(&password)).await;
+    }
+
+    /// Runs the verification outside of the runtime, as verifying a hash is deliberately slow
+    async fn verify_blocking(&self, verify: impl FnOnce() -> bool + Send + 'static) -> bool {
+        let _permit = match self.permits.acquire().await {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+
+        tokio::task::spawn_blocking(verify).await.unwrap_or(false)
+    }
+}
+
+/// Where the credentials of the customers are loaded from
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum CredentialSource {
+    Vault,
+    File,
+    Env,
+}
+
+/// Loads the credentials of all customers, mapping their usernames to their credential
+pub trait CredentialProvider: Send {
+    fn load(&self) -> Result<HashMap<String, Credential>, CredentialError>;
+}
+
+#[derive(Debug)]
+pub enum CredentialError {
+    Vault(vault::VaultError),
+    LoadingFile(config::LoadConfigError),
+    InvalidHash { path: std::path::PathBuf, username: String },
+    MissingEnv(String),
+    InvalidEnvEntry { env: String, entry: usize },
+}
+
+impl std::fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vault(e) => write!(f, "loading credentials from Vault: {:?}", e),
+            Self::LoadingFile(e) => write!(f, "{}", e),
+            Self::InvalidHash { path, username } => write!(
+                f,
+                "{}: the password hash of '{}' is not an Argon2 PHC string",
+                path.display(),
+                username
+            ),
+            Self::MissingEnv(env) => write!(f, "environment variable '{}' is not set", env),
+            Self::InvalidEnvEntry { env, entry } => write!(
+                f,
+                "entry {} of environment variable '{}' is not `username:password`",
+                entry, env
+            ),
+        }
+    }
+}
+
+/// Reads a secret (with a `username` and `password`) per customer below a path in Vault
+pub struct VaultCredentials {
+    vault_client: vault::VaultClient,
+    path: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CustomerData {
+    username: String,
+    password: String,
+}
+
+impl VaultCredentials {
+    pub fn new(vault_client: vault::VaultClient, path: impl Into<String>) -> Self {
+        Self {
+            vault_client,
+            path: path.into(),
+        }
+    }
+}
+
+impl CredentialProvider for VaultCredentials {
+    fn load(&self) -> Result<HashMap<String, Credential>, CredentialError> {
+        let entries = self.vault_client.list(&self.path).map_err(CredentialError::Vault)?;
+
+        let mut result = HashMap::new();
+        for entry in entries {
+            match self
+                .vault_client
+                .read_secret::<CustomerData>(&format!("{}/{}", self.path, entry))
+            {
+                Ok(cdata) => {
+                    result.insert(cdata.username, Credential::Password(cdata.password));
+                }
+                Err(e) => {
+                    tracing::error!(?e, ?entry, "Loading Customer Data from Vault");
+                }
+            };
+        }
+
+        Ok(result)
+    }
+}
+
+/// Reads a TOML file mapping every username to the Argon2 hash of its password, like
+/// `first = "$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>"`
+pub struct FileCredentials {
+    path: std::path::PathBuf,
+}
+
+impl FileCredentials {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl CredentialProvider for FileCredentials {
+    fn load(&self) -> Result<HashMap<String, Credential>, CredentialError> {
+        let content: HashMap<String, String> =
+            config::load_toml(&self.path).map_err(CredentialError::LoadingFile)?;
+
+        content
+            .into_iter()
+            .map(|(username, hash)| match Credential::parse_hashed(&hash) {
+                Some(credential) => Ok((username, credential)),
+                None => Err(CredentialError::InvalidHash {
+                    path: self.path.clone(),
+                    username,
+                }),
+            })
+            .collect()
+    }
+}
+
+/// Reads the credentials from an environment variable, like `first:password,second:password`.
+/// Meant for local development and tests, as the passwords are stored in plain text
+pub struct EnvCredentials {
+    env: String,
+}
+
+impl EnvCredentials {
+    pub fn new(env: impl Into<String>) -> Self {
+        Self { env: env.into() }
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn load(&self) -> Result<HashMap<String, Credential>, CredentialError> {
+        let content =
+            std::env::var(&self.env).map_err(|_e| CredentialError::MissingEnv(self.env.clone()))?;
+
+        content
+            .split(',')
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty())
+            .enumerate()
+            .map(|(idx, entry)| match entry.split_once(':') {
+                Some((username, password)) if !username.is_empty() => Ok((
+                    username.to_string(),
+                    Credential::Password(password.to_string()),
+                )),
+                _ => Err(CredentialError::InvalidEnvEntry {
+                    env: self.env.clone(),
+                    entry: idx,
+                }),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_argon2_hashes() {
+        // Cheap parameters to keep the test fast, they are part of the PHC string
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+        let hash = argon2.hash_password(b"secret").unwrap().to_string();
+
+        let credential = Credential::parse_hashed(&hash).unwrap();
+        assert!(credential.verify("secret"));
+        assert!(!credential.verify("Secret"));
+    }
+
+    #[test]
+    fn remembers_successful_verifications() {
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+        let first = Credential::parse_hashed(&argon2.hash_password(b"secret").unwrap().to_string()).unwrap();
+        let second = Credential::parse_hashed(&argon2.hash_password(b"other").unwrap().to_string()).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let verifier = CredentialVerifier::new();
+
+            assert!(!verifier.verify("first", "wrong", &first).await);
+            assert!(verifier.verify("first", "secret", &first).await);
+            assert_eq!(verifier.cache.lock().unwrap().len(), 1);
+            assert!(verifier.verify("first", "secret", &first).await);
+
+            // A changed credential needs to be verified again
+            assert!(!verifier.verify("first", "secret", &second).await);
+        });
+    }
+
+    #[test]
+    fn rejects_other_hashes() {
+        assert!(Credential::parse_hashed(
+            "sha256:2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        )
+        .is_none());
+        assert!(Credential::parse_hashed("$argon2id$v=19$m=8,t=1,p=1").is_none());
+    }
+}
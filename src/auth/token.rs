@@ -0,0 +1,185 @@
+//! Authenticating developers with GitLab access tokens, for tools like `twine` or `uv publish`
+//! which can't use a session
+
+use std::collections::HashMap;
+
+use sha2::Digest;
+
+/// The base of the GitLab API, used to check the memberships of a token's user
+static GITLAB_API_URL: &str = "https://gitlab.com/api/v4";
+
+/// The lowest GitLab role (Developer) allowed to upload, when allowed through a group or project
+const DEVELOPER_ACCESS_LEVEL: u64 = 30;
+
+/// The GitLab users allowed to authenticate with their access token. A user only has to match one
+/// of the entries, an empty allowlist disables token authentication
+#[derive(Debug, Clone, Default)]
+pub struct TokenAllowlist {
+    /// Usernames of allowed users
+    pub users: Vec<String>,
+    /// Full paths of groups (e.g. `company/python`), in which the user needs at least the
+    /// Developer role
+    pub groups: Vec<String>,
+    /// Full paths of projects, in which the user needs at least the Developer role
+    pub projects: Vec<String>,
+}
+
+impl TokenAllowlist {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty() && self.projects.is_empty()
+    }
+}
+
+/// The SHA-256 digests of accepted tokens, mapped to their user and when they expire. Only the
+/// digests are kept, so the tokens themselves are not stored
+type TokenCache = HashMap<[u8; 32], (String, std::time::Instant)>;
+
+/// Verifies GitLab access tokens against the allowlist, remembering accepted tokens for a while so
+/// not every request calls the GitLab API
+#[derive(Clone)]
+pub struct GitlabTokenAuth {
+    allowlist: std::sync::Arc<TokenAllowlist>,
+    ttl: std::time::Duration,
+    http_client: reqwest::Client,
+    cache: std::sync::Arc<std::sync::Mutex<TokenCache>>,
+}
+
+impl std::fmt::Debug for GitlabTokenAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitlabTokenAuth")
+            .field("allowlist", &self.allowlist)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum TokenAuthError {
+    /// No allowlist is configured
+    Disabled,
+    LoadingUser(reqwest::Error),
+    CheckingMembership(reqwest::Error),
+    NotAllowed { username: String },
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct GitlabMember {
+    access_level: u64,
+}
+
+impl GitlabTokenAuth {
+    pub fn new(allowlist: TokenAllowlist, ttl: std::time::Duration) -> Self {
+        Self {
+            allowlist: std::sync::Arc::new(allowlist),
+            ttl,
+            http_client: reqwest::Client::new(),
+            cache: Default::default(),
+        }
+    }
+
+    /// Checks that the token belongs to an allowed user, returning the user's name
+    pub async fn authenticate(&self, token: &str) -> Result<String, TokenAuthError> {
+        if self.allowlist.is_empty() {
+            return Err(TokenAuthError::Disabled);
+        }
+
+        let digest: [u8; 32] = sha2::Sha256::digest(token.as_bytes()).into();
+        let now = std::time::Instant::now();
+        if let Some((username, _)) = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&digest)
+            .filter(|(_, expires)| *expires > now)
+        {
+            return Ok(username.clone());
+        }
+
+        let user = super::gitlab_user(token)
+            .await
+            .map_err(TokenAuthError::LoadingUser)?;
+        if !self
+            .is_allowed(&user, token)
+            .await
+            .map_err(TokenAuthError::CheckingMembership)?
+        {
+            return Err(TokenAuthError::NotAllowed {
+                username: user.username,
+            });
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, expires)| *expires > now);
+        cache.insert(digest, (user.username.clone(), now + self.ttl));
+
+        Ok(user.username)
+    }
+
+    async fn is_allowed(&self, user: &super::GitlabUser, token: &str) -> Result<bool, reqwest::Error> {
+        if self.allowlist.users.contains(&user.username) {
+            return Ok(true);
+        }
+
+        let memberships = self
+            .allowlist
+            .groups
+            .iter()
+            .map(|group| ("groups", group))
+            .chain(self.allowlist.projects.iter().map(|project| ("projects", project)));
+        for (kind, path) in memberships {
+            let access_level = self.access_level(kind, path, user.id, token).await?;
+            if access_level.is_some_and(|level| level >= DEVELOPER_ACCESS_LEVEL) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Loads the user's role in the group or project (including inherited memberships), `None`
+    /// if the user is not a member or can't see the group or project
+    async fn access_level(
+        &self,
+        kind: &str,
+        path: &str,
+        user_id: u64,
+        token: &str,
+    ) -> Result<Option<u64>, reqwest::Error> {
+        let response = self
+            .http_client
+            .get(member_url(kind, path, user_id)).bearer_auth(token).send().await?;
+        if matches!(
+            response.status(),
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::FORBIDDEN
+        ) {
+            return Ok(None);
+        }
+
+        let member: GitlabMember = response.error_for_status()?.json().await?;
+        Ok(Some(member.access_level))
+    }
+}
+
+/// The API endpoint returning the membership of the user in a group or project
+fn member_url(kind: &str, path: &str, user_id: u64) -> reqwest::Url {
+    let mut url = reqwest::Url::parse(GITLAB_API_URL).expect("The GitLab API URL is valid");
+    url.path_segments_mut()
+        .expect("The GitLab API URL can be a base")
+        // Pushing the path as a single segment encodes its `/` as needed by the API
+        .extend([kind, path, "members", "all", &user_id.to_string()]);
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn member_url_encodes_the_full_path() {
+        assert_eq!(
+            member_url("groups", "company/python", 42).as_str(),
+            "https://gitlab.com/api/v4/groups/company%2Fpython/members/all/42"
+        );
+    }
+}
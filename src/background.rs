@@ -1,11 +1,18 @@
 pub mod customers;
 pub mod packages;
 pub mod customer_auth;
+pub mod dependencies;
+mod index_cache;
+pub mod watch;
 
 #[derive(Debug, Clone)]
 pub struct Notifier(std::sync::mpsc::SyncSender<()>);
 pub struct NotificationReceiver(std::sync::mpsc::Receiver<()>);
 
+/// The other side of the notification channel has been dropped
+#[derive(Debug)]
+pub struct Disconnected;
+
 pub fn notifier() -> (Notifier, NotificationReceiver) {
     let (tx, rx) = std::sync::mpsc::sync_channel(1);
     (Notifier(tx), NotificationReceiver(rx))
@@ -14,20 +21,20 @@ pub fn notifier() -> (Notifier, NotificationReceiver) {
 impl Notifier {
     /// Will attempt to notify the corresponding [`NotificationReceiver`], if there is already a
     /// pending notification, this will do nothing
-    pub fn notify(&self) -> Result<(), ()> {
+    pub fn notify(&self) -> Result<(), Disconnected> {
         match self.0.try_send(()) {
             Ok(_) => Ok(()),
             Err(std::sync::mpsc::TrySendError::Full(_)) => Ok(()),
-            Err(std::sync::mpsc::TrySendError::Disconnected(_)) => Err(()),
+            Err(std::sync::mpsc::TrySendError::Disconnected(_)) => Err(Disconnected),
         }
     }
 }
 
 impl NotificationReceiver {
-    pub fn listen(&mut self) -> Result<(), ()> {
+    pub fn listen(&mut self) -> Result<(), Disconnected> {
         match self.0.recv() {
             Ok(_) => Ok(()),
-            Err(_) => Err(()),
+            Err(_) => Err(Disconnected),
         }
     }
 }
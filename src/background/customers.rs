@@ -1,41 +1,154 @@
-use crate::{State, config};
+use std::collections::HashMap;
 
-use super::NotificationReceiver;
+use crate::{Customer, PackageName, State, config, distribution::WheelFilter, version::AllowedVersions};
 
-#[tracing::instrument(skip(state, recv, config_path))]
+use super::{NotificationReceiver, Notifier};
+
+#[tracing::instrument(skip(state, recv, config_path, package_config_path, dependency_notifier))]
 pub fn customer_updates(
     state: std::sync::Arc<tokio::sync::RwLock<State>>,
     mut recv: NotificationReceiver,
-    config_path: impl Into<std::path::PathBuf>
+    config_path: impl Into<std::path::PathBuf>,
+    package_config_path: impl Into<std::path::PathBuf>,
+    dependency_notifier: Notifier,
 ) {
     let config_path = config_path.into();
+    let package_config_path = package_config_path.into();
+    let mut last_config: Option<config::CustomerConfig> = None;
 
     loop {
         if let Err(e) = recv.listen() {
-            tracing::error!("NotificationReceiver is broken");
+            tracing::error!(?e, "NotificationReceiver is broken");
             return;
         }
 
         tracing::trace!("Reloading Customer configuration");
 
-        let customer_config = match load_customers(&config_path) {
-            Ok(c) => c,
+        // The packages are only needed to validate the customers, so an invalid package config
+        // only skips that part of the validation
+        let package_config = match config::PackageConfiguration::load(&package_config_path) {
+            Ok(c) => Some(c),
             Err(e) => {
-                tracing::error!(?e, "Loading Customer Config");
-                continue;
+                tracing::warn!(%e, "Not validating the packages of customers");
+                None
+            }
+        };
+
+        match config::CustomerConfig::load(&config_path, package_config.as_ref()) {
+            Ok(c) => last_config = Some(c),
+            Err(e) => {
+                tracing::error!(%e, "Loading Customer Config, keeping the last valid one");
             }
         };
+        let customer_config = match last_config.as_ref() {
+            Some(c) => c,
+            None => continue,
+        };
+
+        let mut customers: HashMap<String, Customer> = customer_config
+            .customers
+            .iter()
+            .map(|(cname, entry)| (cname.clone(), resolve_customer(cname, entry, &customer_config.bundle)))
+            .collect();
+
+        // Replace all the customers at once, so customers removed from the config lose their
+        // access immediately. The dependencies are resolved afterwards, as that may need requests
+        // upstream, until then the previously resolved dependencies are kept
         {
             let mut state = state.blocking_write();
-            for (cname, customer_entry) in customer_config.customers {
-                let new_value = customer_entry.packages.into_iter().collect();
-                state.customer_packages.insert(cname, new_value);
+            for (cname, customer) in customers.iter_mut() {
+                if let Some(previous) = state.customers.get(cname) {
+                    keep_dependencies(previous, customer);
+                }
             }
+
+            log_customer_changes(&state.customers, &customers);
+            state.customers = customers;
+        }
+
+        if let Err(e) = dependency_notifier.notify() {
+            tracing::error!(?e, "Could not notify dependency resolution");
+        }
+    }
+}
+
+/// Takes over the dependencies resolved for the previous version of the customer
+fn keep_dependencies(previous: &Customer, customer: &mut Customer) {
+    if !customer.include_dependencies {
+        return;
+    }
+
+    for dependency in &previous.dependencies {
+        if !customer.packages.contains_key(dependency) {
+            customer
+                .packages
+                .insert(dependency.clone(), AllowedVersions::all());
+            customer.dependencies.insert(dependency.clone());
         }
     }
 }
 
-fn load_customers(path: impl AsRef<std::path::Path>) -> Result<config::CustomerConfig, ()> {
-    let content = std::fs::read_to_string(path).map_err(|e| ())?;
-    toml::from_str(content.as_str()).map_err(|e| ())
+fn log_customer_changes(previous: &HashMap<String, Customer>, current: &HashMap<String, Customer>) {
+    for (cname, customer) in current {
+        match previous.get(cname) {
+            None => tracing::info!(?cname, packages = customer.packages.len(), "Added customer"),
+            Some(old) if old != customer => {
+                tracing::info!(?cname, packages = customer.packages.len(), "Changed customer")
+            }
+            Some(_) => {}
+        };
+    }
+
+    for cname in previous.keys().filter(|cname| !current.contains_key(*cname)) {
+        tracing::info!(?cname, "Removed customer");
+    }
+}
+
+/// Combines the packages of the customer's bundles with its own packages.
+///
+/// The customer's own entry for a package takes precedence over the bundles, if multiple bundles
+/// contain the same package, the versions allowed by any of them are visible
+fn resolve_customer(
+    cname: &str,
+    entry: &config::ConfigCustomer,
+    bundles: &HashMap<String, config::ConfigBundle>,
+) -> Customer {
+    let mut packages: HashMap<PackageName, AllowedVersions> = HashMap::new();
+
+    for bundle_name in &entry.bundles {
+        let bundle = match bundles.get(bundle_name) {
+            Some(b) => b,
+            None => {
+                tracing::error!(?cname, ?bundle_name, "Customer references unknown bundle");
+                continue;
+            }
+        };
+
+        for (pname, specifiers) in &bundle.packages.0 {
+            if entry.packages.0.contains_key(pname) {
+                continue;
+            }
+
+            match packages.get_mut(pname) {
+                Some(versions) => versions.union(specifiers),
+                None => {
+                    packages.insert(pname.clone(), specifiers.clone().into());
+                }
+            };
+        }
+    }
+
+    for (pname, specifiers) in &entry.packages.0 {
+        packages.insert(pname.clone(), specifiers.clone().into());
+    }
+
+    Customer {
+        packages,
+        include_dependencies: entry.include_dependencies,
+        dependencies: Default::default(),
+        wheel_filter: WheelFilter {
+            platforms: entry.platforms.clone(),
+            python: entry.python.clone(),
+        },
+    }
 }
@@ -0,0 +1,248 @@
+//! Resolves the projects required by the packages of a customer, so they can be exposed to the
+//! customer as well
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use crate::{
+    Customer, PackageFile, PackageName, State, UpstreamError, metadata::CoreMetadata, version::Version,
+    version::AllowedVersions,
+};
+
+use super::NotificationReceiver;
+
+/// Keeps the dependencies of the customers with `include_dependencies` up to date, every time it
+/// is notified.
+///
+/// This runs separately from loading the customers, as loading the metadata of the files may need
+/// many requests upstream. Only the entries added for dependencies are changed, the customers'
+/// own packages are left alone
+#[tracing::instrument(skip(state, recv))]
+pub fn dependency_updates(
+    state: std::sync::Arc<tokio::sync::RwLock<State>>,
+    mut recv: NotificationReceiver,
+) {
+    let mut resolver = DependencyResolver::new(crate::upstream_blocking_client());
+
+    loop {
+        if let Err(e) = recv.listen() {
+            tracing::error!(?e, "NotificationReceiver is broken");
+            return;
+        }
+
+        tracing::trace!("Resolving dependencies of customers");
+
+        let customers: Vec<(String, HashMap<PackageName, AllowedVersions>)> = state
+            .blocking_read()
+            .customers
+            .iter()
+            .filter(|(_, customer)| customer.include_dependencies)
+            .map(|(cname, customer)| {
+                let own = customer
+                    .packages
+                    .iter()
+                    .filter(|(pname, _)| !customer.dependencies.contains(*pname))
+                    .map(|(pname, versions)| (pname.clone(), versions.clone()))
+                    .collect();
+                (cname.clone(), own)
+            })
+            .collect();
+
+        for (cname, packages) in customers {
+            let dependencies = resolver.dependencies(&cname, &packages, &state);
+
+            // The customer may have been replaced in the meantime, which is fine as that triggers
+            // another round with its current packages
+            if let Some(customer) = state.blocking_write().customers.get_mut(&cname) {
+                set_dependencies(customer, dependencies);
+            }
+        }
+
+        resolver.retain_used();
+    }
+}
+
+/// Replaces the packages added to the customer as dependencies, without touching its own packages
+fn set_dependencies(customer: &mut Customer, dependencies: HashSet<PackageName>) {
+    for stale in customer.dependencies.difference(&dependencies) {
+        customer.packages.remove(stale);
+    }
+
+    let mut added = HashSet::new();
+    for dependency in dependencies {
+        if customer.dependencies.contains(&dependency) || !customer.packages.contains_key(&dependency) {
+            customer
+                .packages
+                .insert(dependency.clone(), AllowedVersions::all());
+            added.insert(dependency);
+        }
+    }
+    customer.dependencies = added;
+}
+
+struct DependencyResolver {
+    http_client: reqwest::blocking::Client,
+    /// The projects required by a file, keyed by the filename and digest of the file
+    cache: HashMap<String, Vec<PackageName>>,
+    used: HashSet<String>,
+    /// The last report for every customer, so only changes are logged
+    reports: HashMap<String, DependencyReport>,
+}
+
+/// The projects added to a customer, mapping every dependency to the package requiring it
+#[derive(Debug, Default, PartialEq)]
+struct DependencyReport {
+    added: BTreeMap<PackageName, PackageName>,
+    /// Required projects which are not configured, so they could not be added
+    missing: BTreeMap<PackageName, PackageName>,
+}
+
+impl DependencyResolver {
+    fn new(http_client: reqwest::blocking::Client) -> Self {
+        Self {
+            http_client,
+            cache: HashMap::new(),
+            used: HashSet::new(),
+            reports: HashMap::new(),
+        }
+    }
+
+    /// All the configured projects the packages (transitively) depend on, which are not part of
+    /// the packages themselves
+    fn dependencies(
+        &mut self,
+        cname: &str,
+        packages: &HashMap<PackageName, AllowedVersions>,
+        state: &tokio::sync::RwLock<State>,
+    ) -> HashSet<PackageName> {
+        let mut queue: VecDeque<PackageName> = packages.keys().cloned().collect();
+        let mut seen: HashSet<PackageName> = queue.iter().cloned().collect();
+        let mut report = DependencyReport::default();
+
+        while let Some(pname) = queue.pop_front() {
+            // Only hold the lock briefly, as loading the metadata may need requests upstream
+            let files = match state.blocking_read().packages.get(&pname) {
+                Some(package) => package.files.clone(),
+                None => continue,
+            };
+
+            let versions = packages
+                .get(&pname)
+                .cloned()
+                .unwrap_or_else(AllowedVersions::all);
+            for file in representative_files(&files, &versions) {
+                let dependencies = match self.file_dependencies(file) {
+                    Some(d) => d,
+                    None => continue,
+                };
+
+                for dependency in dependencies {
+                    if !seen.insert(dependency.clone()) {
+                        continue;
+                    }
+
+                    if state.blocking_read().packages.contains_key(&dependency) {
+                        report.added.insert(dependency.clone(), pname.clone());
+                        queue.push_back(dependency);
+                    } else {
+                        report.missing.insert(dependency, pname.clone());
+                    }
+                }
+            }
+        }
+
+        let dependencies = report.added.keys().cloned().collect();
+
+        if self.reports.get(cname) != Some(&report) {
+            let added: Vec<String> = report
+                .added
+                .iter()
+                .map(|(dependency, required_by)| format!("{} (required by {})", dependency, required_by))
+                .collect();
+            tracing::info!(?cname, ?added, "Exposing dependencies to customer");
+
+            if !report.missing.is_empty() {
+                let missing: Vec<String> = report
+                    .missing
+                    .iter()
+                    .map(|(dependency, required_by)| format!("{} (required by {})", dependency, required_by))
+                    .collect();
+                tracing::warn!(?cname, ?missing, "Dependencies of customer are not configured");
+            }
+
+            self.reports.insert(cname.to_string(), report);
+        }
+
+        dependencies
+    }
+
+    /// The projects required by the file, `None` if its metadata is not available
+    fn file_dependencies(&mut self, file: &PackageFile) -> Option<Vec<PackageName>> {
+        let key = match crate::preferred_hash(file.hashes()) {
+            Some((name, digest)) => format!("{}#{}={}", file.name(), name, digest),
+            None => file.name().to_string(),
+        };
+        self.used.insert(key.clone());
+
+        if let Some(dependencies) = self.cache.get(&key) {
+            return Some(dependencies.clone());
+        }
+
+        let content = match file {
+            PackageFile::FilePackage { metadata: Some(metadata), .. } => metadata.to_vec(),
+            PackageFile::FilePackage { .. } => return None,
+            PackageFile::RemotePackage { url, auth, .. } => {
+                let mut url = url.clone();
+                url.set_path(&format!("{}.metadata", url.path()));
+
+                tracing::debug!(%url, "Loading metadata");
+                let response = auth
+                    .send_blocking(url, |url| self.http_client.get(url))
+                    .and_then(|r| r.error_for_status().map_err(UpstreamError::Request))
+                    .and_then(|r| r.bytes().map_err(UpstreamError::Request));
+
+                match response {
+                    Ok(content) => content.to_vec(),
+                    Err(e) => {
+                        tracing::warn!(?e, file = file.name(), "Loading metadata of file");
+                        return None;
+                    }
+                }
+            }
+        };
+
+        let dependencies = CoreMetadata::parse(&content).required_projects();
+        self.cache.insert(key, dependencies.clone());
+
+        Some(dependencies)
+    }
+
+    /// Removes all the cached files, which were not used since the last call
+    fn retain_used(&mut self) {
+        let used = std::mem::take(&mut self.used);
+        self.cache.retain(|key, _| used.contains(key));
+    }
+}
+
+/// Picks a single file with available metadata for every allowed version, as all the files of a
+/// version are expected to have the same dependencies
+fn representative_files<'f>(
+    files: &'f [PackageFile],
+    versions: &AllowedVersions,
+) -> Vec<&'f PackageFile> {
+    let mut by_version: BTreeMap<&Version, &PackageFile> = BTreeMap::new();
+
+    for file in files {
+        let distribution = match file.distribution() {
+            Some(d) => d,
+            None => continue,
+        };
+
+        if !versions.contains(&distribution.version) || file.attributes().core_metadata.is_none() {
+            continue;
+        }
+
+        by_version.entry(&distribution.version).or_insert(file);
+    }
+
+    by_version.into_values().collect()
+}
@@ -0,0 +1,151 @@
+//! Caches the pages loaded from upstream indexes, so they can be revalidated using conditional
+//! requests instead of downloading and parsing them again on every reload
+
+use std::collections::{HashMap, HashSet};
+
+use crate::Package;
+
+/// A cached page of an upstream index
+pub struct CachedPage {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// The final URL of the page, after following redirects
+    pub page_url: reqwest::Url,
+    pub body: String,
+    /// The package parsed from the body, only `None` after the cache was restored from disk
+    pub package: Option<Package>,
+}
+
+/// The representation of a [`CachedPage`] stored on disk
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PersistedPage {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    page_url: String,
+    body: String,
+}
+
+pub struct IndexCache {
+    path: Option<std::path::PathBuf>,
+    pages: HashMap<String, CachedPage>,
+    used: HashSet<String>,
+    changed: bool,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum PersistIndexCacheError {
+    Io(std::io::Error),
+    Serialize(serde_json::Error),
+}
+
+impl IndexCache {
+    /// Creates the cache, restoring the pages persisted at the path if there are any
+    pub fn load(path: Option<std::path::PathBuf>) -> Self {
+        let mut pages = HashMap::new();
+
+        if let Some(path) = path.as_ref() {
+            match std::fs::read(path) {
+                Ok(content) => match serde_json::from_slice::<HashMap<String, PersistedPage>>(&content) {
+                    Ok(persisted) => {
+                        for (url, page) in persisted {
+                            let page_url = match reqwest::Url::parse(&page.page_url) {
+                                Ok(u) => u,
+                                Err(e) => {
+                                    tracing::warn!(?e, ?url, "Ignoring cached page with invalid URL");
+                                    continue;
+                                }
+                            };
+
+                            pages.insert(
+                                url,
+                                CachedPage {
+                                    etag: page.etag,
+                                    last_modified: page.last_modified,
+                                    page_url,
+                                    body: page.body,
+                                    package: None,
+                                },
+                            );
+                        }
+
+                        tracing::info!(?path, pages = pages.len(), "Restored index cache");
+                    }
+                    Err(e) => {
+                        tracing::error!(?e, ?path, "Parsing index cache");
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    tracing::error!(?e, ?path, "Reading index cache");
+                }
+            };
+        }
+
+        Self {
+            path,
+            pages,
+            used: HashSet::new(),
+            changed: false,
+        }
+    }
+
+    pub fn get_mut(&mut self, url: &reqwest::Url) -> Option<&mut CachedPage> {
+        self.used.insert(url.to_string());
+        self.pages.get_mut(url.as_str())
+    }
+
+    pub fn insert(&mut self, url: &reqwest::Url, page: CachedPage) {
+        self.used.insert(url.to_string());
+        self.pages.insert(url.to_string(), page);
+        self.changed = true;
+    }
+
+    /// Removes all the pages which were not used since the last call and persists the cache, if
+    /// it changed
+    pub fn finish_round(&mut self) -> Result<(), PersistIndexCacheError> {
+        let used = std::mem::take(&mut self.used);
+        let before = self.pages.len();
+        self.pages.retain(|url, _| used.contains(url));
+        self.changed |= before != self.pages.len();
+
+        if !self.changed {
+            return Ok(());
+        }
+
+        let path = match self.path.as_ref() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        let persisted: HashMap<&str, PersistedPage> = self
+            .pages
+            .iter()
+            .map(|(url, page)| {
+                (
+                    url.as_str(),
+                    PersistedPage {
+                        etag: page.etag.clone(),
+                        last_modified: page.last_modified.clone(),
+                        page_url: page.page_url.to_string(),
+                        body: page.body.clone(),
+                    },
+                )
+            })
+            .collect();
+
+        let content = serde_json::to_vec(&persisted).map_err(PersistIndexCacheError::Serialize)?;
+
+        // Write to a temporary file first, so a crash never leaves a truncated cache behind
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        std::fs::write(&temp_path, content).map_err(PersistIndexCacheError::Io)?;
+        std::fs::rename(&temp_path, path).map_err(PersistIndexCacheError::Io)?;
+
+        self.changed = false;
+        tracing::debug!(?path, pages = self.pages.len(), "Persisted index cache");
+
+        Ok(())
+    }
+}
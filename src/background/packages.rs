@@ -1,97 +1,223 @@
 use std::collections::HashMap;
 
 use html5ever::tendril::TendrilSink;
+use sha2::Digest;
 
-use crate::{Package, PackageFile, PackageSrc, State, config};
+use crate::{distribution::{DistributionFilename, DistributionKind}, metadata, vault, FileAttributes, FileHashes, Package, PackageFile, PackageName, PackageSrc, RemotePackageAuth, State, UpstreamError, config};
 
-use super::NotificationReceiver;
+use super::{
+    NotificationReceiver, Notifier,
+    index_cache::{CachedPage, IndexCache},
+    watch::FolderWatcher,
+};
 
-#[tracing::instrument(skip(state, recv, config_path))]
+#[tracing::instrument(skip(state, recv, config_path, index_cache_path, vault_client, customer_notifier, folder_watcher))]
 pub fn package_updates(
     state: std::sync::Arc<tokio::sync::RwLock<State>>,
     mut recv: NotificationReceiver,
-    config_path: impl Into<std::path::PathBuf>
+    config_path: impl Into<std::path::PathBuf>,
+    index_cache_path: Option<std::path::PathBuf>,
+    vault_client: vault::VaultClient,
+    customer_notifier: Notifier,
+    mut folder_watcher: Option<FolderWatcher>,
 ) {
-    let http_client = reqwest::blocking::Client::new();
+    let http_client = crate::upstream_blocking_client();
     let config_path = config_path.into();
+    let mut file_cache = LocalFileCache::new();
+    let mut index_cache = IndexCache::load(index_cache_path);
+    let mut last_config: Option<config::PackageConfiguration> = None;
+    // The last successfully loaded files of every source, used while a source fails to load
+    let mut last_loaded: HashMap<(PackageName, config::PackageSource), Package> = HashMap::new();
 
     loop {
         if let Err(e) = recv.listen() {
-            tracing::error!("NotificationReceiver is broken");
+            tracing::error!(?e, "NotificationReceiver is broken");
             return;
         }
 
         tracing::trace!("Reloading package configuration");
 
-        let config = match config::PackageConfiguration::load(&config_path) {
-            Ok(c) => c,
+        match config::PackageConfiguration::load(&config_path) {
+            Ok(c) => last_config = Some(c),
             Err(e) => {
-                tracing::error!(?e, "Loading Package Configuration");
-                continue;
+                tracing::error!(%e, "Loading Package Configuration, keeping the last valid one");
             }
         };
+        let config = match last_config.as_ref() {
+            Some(c) => c,
+            None => continue,
+        };
+
+        if let Some(watcher) = folder_watcher.as_mut() {
+            watcher.set_folders(config.package.values().flat_map(|package| package.sources()).filter_map(
+                |source| match source {
+                    config::PackageSource::Folder(folder) => Some(folder.into()),
+                    config::PackageSource::Index(_) => None,
+                },
+            ));
+        }
 
-        let mut new_packages: HashMap<String, _> = Default::default();
+        let index_auths = resolve_index_auths(&config.index, &vault_client);
 
-        for (pname, package_config) in config.package {
+        let mut new_packages: HashMap<PackageName, _> = Default::default();
+        let mut new_loaded = HashMap::new();
+
+        for (pname, package_config) in config.package.iter() {
             tracing::trace!(?pname, "Handling package {:?}", package_config);
 
-            if let Some(index_name) = &package_config.index {
-                tracing::trace!("Loading from Index");
-
-                match load_package_index(
-                    &http_client,
-                    &config.index,
-                    &pname,
-                    &index_name,
-                    &package_config,
-                ) {
-                    Ok(package) => {
-                        new_packages.insert(pname.clone(), package);
+            let mut loaded = Vec::new();
+            for source in package_config.sources() {
+                let result = match &source {
+                    config::PackageSource::Index(index_name) => {
+                        tracing::trace!(?index_name, "Loading from Index");
+
+                        load_package_index(
+                            &http_client,
+                            &config.index,
+                            &index_auths,
+                            pname,
+                            index_name,
+                            package_config,
+                            &mut index_cache,
+                        )
+                        .map_err(|e| tracing::error!(?e, "Loading Package from index"))
                     }
-                    Err(e) => {
-                        tracing::error!(?e, "Loading Package from index");
+                    config::PackageSource::Folder(folder) => {
+                        tracing::trace!(?folder, "Loading from folder");
+
+                        load_package_folder(pname, folder, &mut file_cache)
+                            .map_err(|e| tracing::error!(?e, "Loading Package from folder"))
                     }
                 };
-            }
-
-            if let Some(folder) = &package_config.folder {
-                tracing::trace!("Loading from folder");
 
-                match load_package_folder(&pname, folder) {
-                    Ok(package) => {
-                        new_packages.insert(pname.clone(), package);
-                    }
-                    Err(e) => {
-                        tracing::error!(?e, "Loading Package from folder");
-                    }
+                let key = (pname.clone(), source);
+                let package = match result {
+                    Ok(package) => package,
+                    Err(()) => match last_loaded.remove(&key) {
+                        Some(previous) => {
+                            tracing::warn!(?pname, source = ?key.1, "Keeping the previously loaded files of the source");
+                            previous
+                        }
+                        None => continue,
+                    },
                 };
+                new_loaded.insert(key, package.clone());
+                loaded.push(package);
+            }
+
+            if let Some(package) = merge_packages(pname, loaded) {
+                new_packages.insert(pname.clone(), package);
             }
         }
 
+        // Sources no longer configured are dropped here
+        last_loaded = new_loaded;
+        file_cache.retain_used();
+        if let Err(e) = index_cache.finish_round() {
+            tracing::error!(?e, "Persisting index cache");
+        }
+
         {
             let mut state = state.blocking_write();
             state.packages = new_packages;
         }
+
+        // The dependencies exposed to customers depend on the loaded packages
+        if let Err(e) = customer_notifier.notify() {
+            tracing::error!(?e, "Could not notify customer reload");
+        }
     }
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 enum LoadPackageIndexError {
     UnknownIndex(String),
     InvalidIndexUrl,
     JoiningUrls,
-    SendingRequest,
-    ParseResponse(std::io::Error),
+    SendingRequest(UpstreamError),
+    UnexpectedStatus(reqwest::StatusCode),
+    ReadingResponse(reqwest::Error),
+    MissingCredentials(String),
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub(crate) enum ResolveAuthError {
+    MissingEnv(String),
+    Vault(vault::VaultError),
+    InvalidHeader,
+}
+
+/// Resolves the credentials of all the indexes, indexes whose credentials could not be resolved
+/// are left out
+fn resolve_index_auths(
+    index_config: &HashMap<String, config::IndexConfigEntry>,
+    vault_client: &vault::VaultClient,
+) -> HashMap<String, RemotePackageAuth> {
+    index_config
+        .iter()
+        .filter_map(|(name, index)| {
+            let auth = match &index.auth {
+                Some(auth) => match resolve_index_auth(auth, vault_client) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        tracing::error!(?e, ?name, "Resolving credentials of index");
+                        return None;
+                    }
+                },
+                None => RemotePackageAuth::Unauthorized,
+            };
+
+            Some((name.clone(), auth))
+        })
+        .collect()
 }
 
-#[tracing::instrument(skip(http_client, index_config, package_config))]
+/// Resolves the credentials of a single index, reading the secrets from the environment or Vault
+pub(crate) fn resolve_index_auth(
+    auth: &config::IndexAuth,
+    vault_client: &vault::VaultClient,
+) -> Result<RemotePackageAuth, ResolveAuthError> {
+    let resolve = |secret: &config::SecretValue| match secret {
+        config::SecretValue::Plain(value) => Ok(value.clone()),
+        config::SecretValue::Env { env } => {
+            std::env::var(env).map_err(|_e| ResolveAuthError::MissingEnv(env.clone()))
+        }
+        config::SecretValue::Vault { vault } => vault_client
+            .read_key(&vault.path, &vault.key)
+            .map_err(ResolveAuthError::Vault),
+    };
+
+    match auth {
+        config::IndexAuth::Basic { username, password } => Ok(RemotePackageAuth::Basic {
+            username: resolve(username)?,
+            password: password.as_ref().map(resolve).transpose()?,
+        }),
+        config::IndexAuth::Bearer { token } => Ok(RemotePackageAuth::Bearer {
+            token: resolve(token)?,
+        }),
+        config::IndexAuth::Header { name, value } => {
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_e| ResolveAuthError::InvalidHeader)?;
+            let mut value = reqwest::header::HeaderValue::from_str(&resolve(value)?)
+                .map_err(|_e| ResolveAuthError::InvalidHeader)?;
+            value.set_sensitive(true);
+
+            Ok(RemotePackageAuth::Header { name, value })
+        }
+    }
+}
+
+#[tracing::instrument(skip(http_client, index_config, index_auths, package_config, index_cache))]
 fn load_package_index(
     http_client: &reqwest::blocking::Client,
     index_config: &HashMap<String, config::IndexConfigEntry>,
-    pname: &str,
+    index_auths: &HashMap<String, RemotePackageAuth>,
+    pname: &PackageName,
     index_name: &str,
     package_config: &config::PackageConfigEntry,
+    index_cache: &mut IndexCache,
 ) -> Result<Package, LoadPackageIndexError> {
     tracing::trace!(?pname, "Handling package {:?}", package_config);
 
@@ -103,21 +229,151 @@ fn load_package_index(
     tracing::trace!("Using Index {:?}", index);
 
     let base_url =
-        reqwest::Url::parse(&index.url).map_err(|e| LoadPackageIndexError::InvalidIndexUrl)?;
+        reqwest::Url::parse(&index.url).map_err(|_e| LoadPackageIndexError::InvalidIndexUrl)?;
     let target_url = base_url
         .join(&format!("{}/", pname))
-        .map_err(|e| LoadPackageIndexError::JoiningUrls)?;
+        .map_err(|_e| LoadPackageIndexError::JoiningUrls)?;
     tracing::trace!("Loading package files from '{}'", target_url);
 
-    // TODO
-    // Support authentication for the index
+    let auth = index_auths
+        .get(index_name)
+        .ok_or_else(|| LoadPackageIndexError::MissingCredentials(index_name.to_string()))?;
+
+    // Only ask for the page if it changed since we last loaded it
+    let cached = index_cache.get_mut(&target_url);
+    let conditional = cached
+        .as_ref()
+        .map(|cached| (cached.etag.clone(), cached.last_modified.clone()));
+
+    let response = auth
+        .send_blocking(target_url.clone(), |url| {
+            let mut req_builder = http_client.get(url);
+            if let Some((etag, last_modified)) = &conditional {
+                if let Some(etag) = etag {
+                    req_builder = req_builder.header(reqwest::header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = last_modified {
+                    req_builder = req_builder.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            req_builder
+        })
+        .map_err(LoadPackageIndexError::SendingRequest)?;
+
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        let cached = match cached {
+            Some(c) => c,
+            None => return Err(LoadPackageIndexError::UnexpectedStatus(response.status())),
+        };
+        tracing::trace!("Page not modified, reusing previous result");
+
+        // After a restart only the raw page is known, so it still needs to be parsed once
+        let package = cached.package.get_or_insert_with(|| Package {
+            sources: vec![PackageSrc::Index {
+                url: base_url.clone(),
+            }],
+            files: parse_index_page(&cached.page_url, &cached.body),
+        });
+        return Ok(with_auth(package.clone(), &base_url, auth));
+    }
+
+    if !response.status().is_success() {
+        return Err(LoadPackageIndexError::UnexpectedStatus(response.status()));
+    }
+
+    // Links on the page may be relative, so they need to be resolved against the final URL of the
+    // page (after following any redirects)
+    let page_url = response.url().clone();
+
+    let header = |name: reqwest::header::HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+
+    let body = response
+        .text()
+        .map_err(LoadPackageIndexError::ReadingResponse)?;
+
+    let package = Package {
+        sources: vec![PackageSrc::Index { url: base_url.clone() }],
+        files: parse_index_page(&page_url, &body),
+    };
+
+    // Without any validators the page can't be revalidated, so there is no point in keeping it
+    if etag.is_some() || last_modified.is_some() {
+        index_cache.insert(
+            &target_url,
+            CachedPage {
+                etag,
+                last_modified,
+                page_url,
+                body,
+                package: Some(package.clone()),
+            },
+        );
+    }
+
+    Ok(with_auth(package, &base_url, auth))
+}
+
+/// Merges the packages loaded from the different sources, which are ordered by their priority.
+///
+/// Files are de-duplicated by their filename, keeping the one from the source with the highest
+/// priority
+fn merge_packages(pname: &PackageName, loaded: Vec<Package>) -> Option<Package> {
+    if loaded.is_empty() {
+        return None;
+    }
 
-    let req_builder = http_client.get(target_url);
+    let mut sources = Vec::new();
+    let mut files = Vec::new();
+    let mut seen = std::collections::HashSet::new();
 
-    let mut response = req_builder
-        .send()
-        .map_err(|e| LoadPackageIndexError::SendingRequest)?;
+    for package in loaded {
+        let src = package.sources.first().cloned();
+        sources.extend(package.sources);
+
+        for file in package.files {
+            if !seen.insert(file.name().to_string()) {
+                tracing::debug!(?pname, file = file.name(), ?src, "Ignoring file provided by a source with higher priority");
+                continue;
+            }
 
+            files.push(file);
+        }
+    }
+
+    files.sort_by(|a, b| {
+        let a_version = a.distribution().map(|d| &d.version);
+        let b_version = b.distribution().map(|d| &d.version);
+        a_version.cmp(&b_version).then_with(|| a.name().cmp(b.name()))
+    });
+
+    Some(Package { sources, files })
+}
+
+/// Attaches the credentials of the index to all the files hosted on the same origin as the
+/// index, so they are never sent to a third party (like a CDN the files are hosted on)
+fn with_auth(mut package: Package, index_url: &reqwest::Url, auth: &RemotePackageAuth) -> Package {
+    for file in package.files.iter_mut() {
+        match file {
+            PackageFile::RemotePackage { url, auth: file_auth, .. } if url.origin() == index_url.origin() => {
+                *file_auth = auth.clone();
+            }
+            _ => {}
+        };
+    }
+
+    package
+}
+
+/// Parses the files linked on a simple repository page
+fn parse_index_page(page_url: &reqwest::Url, body: &str) -> Vec<PackageFile> {
     let parsing_opts = html5ever::ParseOpts {
         tree_builder: html5ever::tree_builder::TreeBuilderOpts {
             drop_doctype: true,
@@ -127,9 +383,7 @@ fn load_package_index(
     };
 
     let dom = html5ever::parse_document(markup5ever_rcdom::RcDom::default(), parsing_opts)
-        .from_utf8()
-        .read_from(&mut response)
-        .map_err(|e| LoadPackageIndexError::ParseResponse(e))?;
+        .one(body);
 
     let mut files = Vec::new();
 
@@ -140,10 +394,14 @@ fn load_package_index(
                 if "a" == name.local.as_ref() =>
             {
                 let attrs = attrs.borrow();
-                let link = attrs
-                    .as_slice()
-                    .iter()
-                    .find(|attr| attr.name.local.as_ref() == "href");
+                let attr = |attr_name: &str| {
+                    attrs
+                        .as_slice()
+                        .iter()
+                        .find(|attr| attr.name.local.as_ref() == attr_name)
+                        .map(|attr| attr.value.to_string())
+                };
+                let link = attr("href");
 
                 let children = node.children.borrow();
                 let text = children
@@ -156,7 +414,7 @@ fn load_package_index(
                         _ => None,
                     });
 
-                let raw_url: &str = match link.map(|v| v.value.as_ref()) {
+                let raw_url: &str = match link.as_deref() {
                     Some(v) => v,
                     None => {
                         tracing::warn!("");
@@ -172,7 +430,7 @@ fn load_package_index(
                     }
                 };
 
-                let url = match reqwest::Url::parse(raw_url) {
+                let mut url = match page_url.join(raw_url) {
                     Ok(u) => u,
                     Err(e) => {
                         tracing::warn!(?e, "Parse URL in href");
@@ -180,10 +438,38 @@ fn load_package_index(
                     }
                 };
 
+                let hashes = url.fragment().map(parse_hash_fragment).unwrap_or_default();
+                url.set_fragment(None);
+
+                // PEP 714 renamed `data-dist-info-metadata` to `data-core-metadata`, but older
+                // indexes only provide the former
+                let core_metadata = attr("data-core-metadata")
+                    .or_else(|| attr("data-dist-info-metadata"))
+                    .and_then(|value| match value.as_str() {
+                        "false" => None,
+                        "true" => Some(FileHashes::new()),
+                        other => Some(parse_hash_fragment(other)),
+                    });
+
+                let attributes = FileAttributes {
+                    requires_python: attr("data-requires-python"),
+                    yanked: attr("data-yanked"),
+                    core_metadata,
+                    gpg_sig: attr("data-gpg-sig").map(|value| value == "true"),
+                };
+
+                let distribution = DistributionFilename::parse(&name);
+                if distribution.is_none() {
+                    tracing::debug!(?name, "Could not parse distribution filename");
+                }
+
                 files.push(PackageFile::RemotePackage {
                     name,
                     url,
-                    auth: crate::RemotePackageAuth::Unauthorized, // TODO
+                    auth: RemotePackageAuth::Unauthorized,
+                    hashes,
+                    attributes,
+                    distribution,
                 });
             }
             _ => {}
@@ -192,15 +478,103 @@ fn load_package_index(
         stack.extend_from_slice(node.children.borrow().as_slice());
     }
 
-    Ok(Package {
-        src: PackageSrc::Index { url: base_url },
-        files,
-    })
+    files
 }
 
-#[tracing::instrument]
-fn load_package_folder(pname: &str, folder: &str) -> Result<Package, ()> {
-    let file_iter = std::fs::read_dir(folder).map_err(|e| ())?;
+/// Parses the `<hashname>=<hashvalue>` fragment of a file URL (or `data-core-metadata` value)
+fn parse_hash_fragment(fragment: &str) -> FileHashes {
+    let mut hashes = FileHashes::new();
+
+    match fragment.split_once('=') {
+        Some((name, digest)) if crate::FRAGMENT_HASHES.contains(&name) => {
+            hashes.insert(name.to_string(), digest.to_ascii_lowercase());
+        }
+        _ => {
+            tracing::trace!(?fragment, "Ignoring unknown URL fragment");
+        }
+    };
+
+    hashes
+}
+
+/// Caches the digests and metadata of local package files, so unchanged files don't have to be
+/// read again on every reload
+struct LocalFileCache {
+    entries: HashMap<std::path::PathBuf, LocalFileEntry>,
+    used: std::collections::HashSet<std::path::PathBuf>,
+}
+
+#[derive(Clone)]
+struct LocalFileEntry {
+    modified: std::time::SystemTime,
+    sha256: String,
+    /// The core metadata extracted from a wheel and its sha256 digest
+    metadata: Option<(std::sync::Arc<[u8]>, String)>,
+}
+
+impl LocalFileCache {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            used: std::collections::HashSet::new(),
+        }
+    }
+
+    /// Returns the information about the file, only reading the file again if it was modified
+    /// since the cached entry was created
+    fn load(
+        &mut self,
+        path: &std::path::Path,
+        distribution: &DistributionFilename,
+    ) -> Result<LocalFileEntry, std::io::Error> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        self.used.insert(path.to_path_buf());
+
+        if let Some(entry) = self.entries.get(path).filter(|e| e.modified == modified) {
+            return Ok(entry.clone());
+        }
+
+        tracing::debug!(?path, "Hashing file");
+
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = sha2::Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        let sha256 = format!("{:x}", hasher.finalize());
+
+        let metadata = match distribution.kind {
+            DistributionKind::Wheel(_) => match metadata::extract_wheel_metadata(path) {
+                Ok(content) => {
+                    let digest = format!("{:x}", sha2::Sha256::digest(&content));
+                    Some((content.into(), digest))
+                }
+                Err(e) => {
+                    tracing::warn!(?e, ?path, "Extracting metadata from wheel");
+                    None
+                }
+            },
+            DistributionKind::SourceDist => None,
+        };
+
+        let entry = LocalFileEntry {
+            modified,
+            sha256,
+            metadata,
+        };
+        self.entries.insert(path.to_path_buf(), entry.clone());
+
+        Ok(entry)
+    }
+
+    /// Removes all the entries, which were not used since the last call
+    fn retain_used(&mut self) {
+        let used = std::mem::take(&mut self.used);
+        self.entries.retain(|path, _| used.contains(path));
+    }
+}
+
+#[tracing::instrument(skip(file_cache))]
+fn load_package_folder(pname: &PackageName, folder: &str, file_cache: &mut LocalFileCache) -> Result<Package, ()> {
+    let file_iter = std::fs::read_dir(folder).map_err(|_e| ())?;
 
     let mut files = Vec::new();
 
@@ -227,34 +601,54 @@ fn load_package_folder(pname: &str, folder: &str) -> Result<Package, ()> {
             }
         };
 
-        if !entry
-            .path()
-            .extension()
-            .map(|ext| ext == "whl")
-            .unwrap_or(false)
-        {
-            tracing::trace!(?file_name, "Ignoring file not ending with 'whl'");
-            continue;
-        }
-
-        let (package_name, _) = match file_name.split_once('-') {
-            Some(v) => v,
+        let distribution = match DistributionFilename::parse(file_name) {
+            Some(d) => d,
             None => {
-                tracing::warn!(?file_name, "Ignoring malformed file name");
+                tracing::trace!(?file_name, "Ignoring file that is not a wheel or sdist");
                 continue;
             }
         };
 
-        if package_name == pname {
+        if &distribution.name == pname {
+            let path = entry.path();
+
+            let cached = match file_cache.load(&path, &distribution) {
+                Ok(c) => c,
+                Err(e) => {
+                    tracing::error!(?e, ?path, "Reading package file");
+                    continue;
+                }
+            };
+
+            let mut hashes = FileHashes::new();
+            hashes.insert("sha256".to_string(), cached.sha256);
+
+            let mut attributes = FileAttributes::default();
+            if let Some((content, digest)) = &cached.metadata {
+                attributes.requires_python = metadata::CoreMetadata::parse(content)
+                    .get("Requires-Python")
+                    .map(|v| v.to_string());
+
+                let mut metadata_hashes = FileHashes::new();
+                metadata_hashes.insert("sha256".to_string(), digest.clone());
+                attributes.core_metadata = Some(metadata_hashes);
+            }
+
             files.push(PackageFile::FilePackage {
                 name: file_name.to_string(),
-                path: entry.path(),
+                path,
+                hashes,
+                attributes,
+                distribution,
+                metadata: cached.metadata.map(|(content, _)| content),
             });
         }
     }
 
     Ok(Package {
-        src: PackageSrc::Folder,
+        sources: vec![PackageSrc::Folder {
+            path: folder.into(),
+        }],
         files,
     })
 }
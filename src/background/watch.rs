@@ -0,0 +1,207 @@
+//! Watches the configuration files and package folders, notifying the corresponding loaders once
+//! they changed
+
+use notify::Watcher;
+
+use super::Notifier;
+
+/// How long to wait for further events before notifying, as a single save often results in
+/// multiple events (editors writing a temporary file and renaming it, truncating and writing, ...)
+const DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Keeps watching the files as long as it is not dropped
+pub struct ConfigWatcher {
+    _watcher: notify::RecommendedWatcher,
+}
+
+/// Watches the files, notifying the corresponding notifiers once a file changed.
+///
+/// The parent directories are watched instead of the files themselves, so files replaced by a
+/// rename (like most editors do) are still picked up. Kubernetes mounts ConfigMaps and Secrets as
+/// symlinks into a `..data` directory and updates them by swapping that symlink, so no event names
+/// the file itself. So on any event in the directory, the target and modification time of the
+/// file are compared with the previous ones as well
+pub fn watch_files(
+    files: Vec<(std::path::PathBuf, Notifier)>,
+) -> Result<ConfigWatcher, notify::Error> {
+    let files: Vec<(std::path::PathBuf, Notifier)> = files
+        .into_iter()
+        .map(|(path, notifier)| std::path::absolute(&path).map(|path| (path, notifier)))
+        .collect::<Result<_, _>>()
+        .map_err(notify::Error::io)?;
+
+    // The index of the file and whether the event was for the file itself
+    let (tx, rx) = std::sync::mpsc::channel::<(usize, bool)>();
+
+    let paths: Vec<std::path::PathBuf> = files.iter().map(|(path, _)| path.clone()).collect();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(e) => e,
+            Err(e) => {
+                tracing::error!(?e, "Watching config files");
+                return;
+            }
+        };
+
+        if event.kind.is_access() {
+            return;
+        }
+
+        for (idx, path) in paths.iter().enumerate() {
+            let exact = event.paths.iter().any(|p| p == path);
+            if exact || event.paths.iter().any(|p| p.parent() == path.parent()) {
+                let _ = tx.send((idx, exact));
+            }
+        }
+    })?;
+
+    let mut dirs: Vec<&std::path::Path> = files.iter().filter_map(|(path, _)| path.parent()).collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        tracing::debug!(?dir, "Watching directory for config changes");
+        watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+    }
+
+    std::thread::spawn(move || debounce(files, rx));
+
+    Ok(ConfigWatcher { _watcher: watcher })
+}
+
+/// Where the file currently resolves to (following all symlinks) and when it was last modified,
+/// `None` if it doesn't exist
+fn file_state(path: &std::path::Path) -> Option<(std::path::PathBuf, std::time::SystemTime)> {
+    let resolved = std::fs::canonicalize(path).ok()?;
+    let modified = std::fs::metadata(&resolved).and_then(|m| m.modified()).ok()?;
+    Some((resolved, modified))
+}
+
+fn debounce(files: Vec<(std::path::PathBuf, Notifier)>, rx: std::sync::mpsc::Receiver<(usize, bool)>) {
+    let mut states: Vec<_> = files.iter().map(|(path, _)| file_state(path)).collect();
+
+    // Waiting for the first change, the channel is only closed once the watcher was dropped
+    while let Ok(first) = rx.recv() {
+        let mut changed = std::collections::BTreeMap::from([first]);
+        while let Ok((idx, exact)) = rx.recv_timeout(DEBOUNCE) {
+            *changed.entry(idx).or_default() |= exact;
+        }
+
+        for (idx, exact) in changed {
+            let (path, notifier) = &files[idx];
+
+            // Events for other files in the directory only count if this file changed as well
+            let state = file_state(path);
+            if !exact && state == states[idx] {
+                continue;
+            }
+            states[idx] = state;
+            tracing::info!(?path, "Config file changed");
+
+            if let Err(e) = notifier.notify() {
+                tracing::error!(?e, ?path, "Could not notify config reload");
+            }
+        }
+    }
+}
+
+/// Watches the folders packages are loaded from, notifying the package loader once files in them
+/// were added, changed or removed
+pub struct FolderWatcher {
+    watcher: notify::RecommendedWatcher,
+    folders: std::collections::BTreeSet<std::path::PathBuf>,
+}
+
+impl FolderWatcher {
+    pub fn new(notifier: Notifier) -> Result<Self, notify::Error> {
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            match event {
+                // Loading the packages reads the files, which must not trigger another reload
+                Ok(e) if e.kind.is_access() => {}
+                Ok(_) => {
+                    let _ = tx.send(());
+                }
+                Err(e) => tracing::error!(?e, "Watching package folders"),
+            };
+        })?;
+
+        std::thread::spawn(move || {
+            while let Ok(()) = rx.recv() {
+                while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+                tracing::info!("Package folder changed");
+                if let Err(e) = notifier.notify() {
+                    tracing::error!(?e, "Could not notify package reload");
+                }
+            }
+        });
+
+        Ok(Self {
+            watcher,
+            folders: Default::default(),
+        })
+    }
+
+    /// Watches exactly the given folders. Folders that can't be watched (like ones not created
+    /// yet) are tried again on the next call
+    pub fn set_folders(&mut self, folders: impl IntoIterator<Item = std::path::PathBuf>) {
+        let folders: std::collections::BTreeSet<std::path::PathBuf> = folders
+            .into_iter()
+            .filter_map(|folder| std::path::absolute(folder).ok())
+            .collect();
+
+        for removed in self.folders.difference(&folders) {
+            tracing::debug!(?removed, "No longer watching package folder");
+            if let Err(e) = self.watcher.unwatch(removed) {
+                tracing::debug!(?e, ?removed, "Unwatching package folder");
+            }
+        }
+
+        let mut watched = std::collections::BTreeSet::new();
+        for folder in folders {
+            if !self.folders.contains(&folder) {
+                if let Err(e) = self.watcher.watch(&folder, notify::RecursiveMode::NonRecursive) {
+                    tracing::warn!(?e, ?folder, "Watching package folder");
+                    continue;
+                }
+                tracing::debug!(?folder, "Watching package folder for changes");
+            }
+            watched.insert(folder);
+        }
+        self.folders = watched;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_up_swapped_symlinks() {
+        let dir = std::env::temp_dir().join(format!("cypi-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // The layout of a Kubernetes Secret mount
+        std::fs::create_dir_all(dir.join("..v1")).unwrap();
+        std::fs::write(dir.join("..v1/tls.crt"), "first").unwrap();
+        std::os::unix::fs::symlink("..v1", dir.join("..data")).unwrap();
+        std::os::unix::fs::symlink("..data/tls.crt", dir.join("tls.crt")).unwrap();
+
+        let (notifier, recv) = super::super::notifier();
+        let _watcher = watch_files(vec![(dir.join("tls.crt"), notifier)]).unwrap();
+        let changed = || recv.0.recv_timeout(DEBOUNCE * 4).is_ok();
+
+        // Unrelated files in the directory don't count as a change
+        std::fs::write(dir.join("other"), "other").unwrap();
+        assert!(!changed());
+
+        std::fs::create_dir_all(dir.join("..v2")).unwrap();
+        std::fs::write(dir.join("..v2/tls.crt"), "second").unwrap();
+        std::os::unix::fs::symlink("..v2", dir.join("..data_tmp")).unwrap();
+        std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+        assert!(changed());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,582 @@
+//! The on-disk cache for files downloaded from upstream indexes
+
+use std::collections::{HashMap, HashSet};
+
+use sha2::Digest;
+use tokio::io::AsyncWriteExt;
+
+use crate::{FileHashes, PackageName};
+
+/// A size limited cache of upstream files, evicting the least recently used files once the
+/// limit is exceeded.
+///
+/// Files are stored as `{dir}/{package}/{filename}`, while being written they are stored in a
+/// temporary file next to it and only moved in place once their digest has been verified
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    inner: std::sync::Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    dir: std::path::PathBuf,
+    max_size: u64,
+    index: std::sync::Mutex<CacheIndex>,
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<std::path::PathBuf, CacheEntry>,
+    in_progress: HashSet<std::path::PathBuf>,
+    total_size: u64,
+    /// Increased on every access, used as the timestamp for the LRU eviction
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    size: u64,
+    last_access: u64,
+    /// The digest the file was verified with, unknown for files picked up after a restart
+    digest: Option<FileDigest>,
+}
+
+/// The digest of a file, using one of the algorithms supported by the [`Verifier`]
+#[derive(Debug, Clone, PartialEq)]
+struct FileDigest {
+    algorithm: &'static str,
+    /// Lowercase hex
+    value: String,
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    DigestMismatch {
+        algorithm: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// The suffix used for files that are still being written
+const PARTIAL_SUFFIX: &str = ".part";
+
+impl FileCache {
+    /// Opens the cache in the given directory, picking up all the files already stored there
+    pub fn open(dir: impl Into<std::path::PathBuf>, max_size: u64) -> Result<Self, std::io::Error> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        // Restore the order of previous accesses from the modification times, which are updated
+        // on every cache hit
+        let mut files = Vec::new();
+        for package_dir in std::fs::read_dir(&dir)? {
+            let package_dir = package_dir?;
+            if !package_dir.file_type()?.is_dir() {
+                continue;
+            }
+
+            for file in std::fs::read_dir(package_dir.path())? {
+                let file = file?;
+                let path = file.path();
+
+                if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                    tracing::debug!(?path, "Removing partially written cache file");
+                    std::fs::remove_file(&path)?;
+                    continue;
+                }
+
+                let metadata = file.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+
+                files.push((path, metadata.len(), metadata.modified()?));
+            }
+        }
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut index = CacheIndex::default();
+        for (path, size, _) in files {
+            index.clock += 1;
+            index.total_size += size;
+            index.entries.insert(
+                path,
+                CacheEntry {
+                    size,
+                    last_access: index.clock,
+                    digest: None,
+                },
+            );
+        }
+
+        tracing::info!(
+            ?dir,
+            files = index.entries.len(),
+            size = index.total_size,
+            "Opened file cache"
+        );
+
+        let cache = Self {
+            inner: std::sync::Arc::new(Inner {
+                dir,
+                max_size,
+                index: std::sync::Mutex::new(index),
+            }),
+        };
+        for path in cache.evict() {
+            remove_evicted(&path);
+        }
+
+        Ok(cache)
+    }
+
+    /// The path of the file in the cache, `None` if the names can't be used as plain file names
+    fn path(&self, package: &PackageName, filename: &str) -> Option<std::path::PathBuf> {
+        let is_plain = |name: &str| !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']);
+        if !is_plain(package.as_str()) || !is_plain(filename) {
+            tracing::warn!(?package, ?filename, "Not caching file with unexpected name");
+            return None;
+        }
+
+        Some(self.inner.dir.join(package.as_str()).join(filename))
+    }
+
+    /// Opens the cached file, if it is in the cache and matches the expected digest.
+    ///
+    /// A file whose digest doesn't match (as the upstream file changed) is removed from the cache
+    pub async fn get(
+        &self,
+        package: &PackageName,
+        filename: &str,
+        expected: &FileHashes,
+    ) -> Option<tokio::fs::File> {
+        let path = self.path(package, filename)?;
+
+        let digest = {
+            let mut index = self.inner.index.lock().unwrap();
+            index.clock += 1;
+            let clock = index.clock;
+            let entry = index.entries.get_mut(&path)?;
+            entry.last_access = clock;
+            entry.digest.clone()
+        };
+
+        let verifier = Verifier::new(expected);
+        let matches = match digest.as_ref().and_then(|digest| verifier.check(digest)) {
+            Some(matches) => matches,
+            // The file has not been verified with this algorithm yet, which only happens for the
+            // files picked up after a restart
+            None => {
+                let hash_path = path.clone();
+                let hashed = tokio::task::spawn_blocking(move || hash_file(&hash_path, verifier))
+                    .await
+                    .map_err(|e| CacheError::Io(std::io::Error::other(e)))
+                    .and_then(|result| result);
+
+                match hashed {
+                    Ok(digest) => {
+                        if let Some(entry) = self.inner.index.lock().unwrap().entries.get_mut(&path) {
+                            entry.digest = digest;
+                        }
+                        true
+                    }
+                    Err(CacheError::DigestMismatch { .. }) => false,
+                    Err(CacheError::Io(e)) => {
+                        tracing::warn!(?e, ?path, "Hashing cached file");
+                        false
+                    }
+                }
+            }
+        };
+
+        if !matches {
+            tracing::info!(?path, "Cached file does not match the expected digest, removing it");
+            self.remove(&path);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                tracing::warn!(?e, ?path, "Removing outdated cached file");
+            }
+            return None;
+        }
+
+        match tokio::fs::File::open(&path).await {
+            Ok(file) => {
+                // Only used to restore the access order after a restart, so failing is fine
+                if let Ok(clone) = file.try_clone().await {
+                    let clone = clone.into_std().await;
+                    tokio::task::spawn_blocking(move || {
+                        let _ = clone.set_modified(std::time::SystemTime::now());
+                    });
+                }
+                Some(file)
+            }
+            Err(e) => {
+                tracing::warn!(?e, ?path, "Opening cached file");
+                self.remove(&path);
+                None
+            }
+        }
+    }
+
+    /// Starts writing a file to the cache, returns `None` if the file is already being written
+    pub async fn writer(
+        &self,
+        package: &PackageName,
+        filename: &str,
+        expected: &FileHashes,
+    ) -> Result<Option<CacheWriter>, std::io::Error> {
+        let path = match self.path(package, filename) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        {
+            let mut index = self.inner.index.lock().unwrap();
+            if index.entries.contains_key(&path) || !index.in_progress.insert(path.clone()) {
+                return Ok(None);
+            }
+        }
+
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(PARTIAL_SUFFIX);
+        let temp_path = path.with_file_name(temp_name);
+
+        let file = async {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::File::create(&temp_path).await
+        }
+        .await;
+
+        let file = match file {
+            Ok(f) => f,
+            Err(e) => {
+                self.inner.index.lock().unwrap().in_progress.remove(&path);
+                return Err(e);
+            }
+        };
+
+        Ok(Some(CacheWriter {
+            cache: self.clone(),
+            path,
+            temp_path,
+            file: Some(file),
+            size: 0,
+            verifier: Verifier::new(expected),
+        }))
+    }
+
+    fn remove(&self, path: &std::path::Path) {
+        let mut index = self.inner.index.lock().unwrap();
+        if let Some(entry) = index.entries.remove(path) {
+            index.total_size -= entry.size;
+        }
+    }
+
+    /// Removes the least recently used files from the index until the cache is within its size
+    /// limit again, returning the files that need to be deleted.
+    ///
+    /// The files are not deleted here, so the lock is not held while touching the disk
+    fn evict(&self) -> Vec<std::path::PathBuf> {
+        let mut index = self.inner.index.lock().unwrap();
+        let mut evicted = Vec::new();
+
+        while index.total_size > self.inner.max_size {
+            let oldest = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(path, _)| path.clone());
+
+            let path = match oldest {
+                Some(p) => p,
+                None => break,
+            };
+
+            if let Some(entry) = index.entries.remove(&path) {
+                index.total_size -= entry.size;
+            }
+
+            tracing::debug!(?path, "Evicting file from cache");
+            evicted.push(path);
+        }
+
+        evicted
+    }
+}
+
+fn remove_evicted(path: &std::path::Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        tracing::error!(?e, ?path, "Removing evicted file from cache");
+    }
+}
+
+/// Computes the digest of the file, verifying it against the expected one
+fn hash_file(path: &std::path::Path, mut verifier: Verifier) -> Result<Option<FileDigest>, CacheError> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path).map_err(CacheError::Io)?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).map_err(CacheError::Io)?;
+        if read == 0 {
+            break;
+        }
+        verifier.update(&buffer[..read]);
+    }
+
+    verifier.verify()
+}
+
+/// Writes a single file into the cache, the file is discarded if the writer is dropped before
+/// [`CacheWriter::finish`] is called
+pub struct CacheWriter {
+    cache: FileCache,
+    path: std::path::PathBuf,
+    temp_path: std::path::PathBuf,
+    file: Option<tokio::fs::File>,
+    size: u64,
+    verifier: Verifier,
+}
+
+impl CacheWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), std::io::Error> {
+        let file = self.file.as_mut().expect("The file is only taken when finishing");
+        file.write_all(chunk).await?;
+        self.verifier.update(chunk);
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Verifies the digest of the written file and moves it into the cache
+    pub async fn finish(mut self) -> Result<(), CacheError> {
+        let mut file = self.file.take().expect("The file is only taken when finishing");
+        file.flush().await.map_err(CacheError::Io)?;
+        file.sync_all().await.map_err(CacheError::Io)?;
+        drop(file);
+
+        let digest = std::mem::replace(&mut self.verifier, Verifier::None).verify()?;
+
+        tokio::fs::rename(&self.temp_path, &self.path)
+            .await
+            .map_err(CacheError::Io)?;
+
+        {
+            let mut index = self.cache.inner.index.lock().unwrap();
+            index.in_progress.remove(&self.path);
+            index.clock += 1;
+            let clock = index.clock;
+            index.total_size += self.size;
+            index.entries.insert(
+                self.path.clone(),
+                CacheEntry {
+                    size: self.size,
+                    last_access: clock,
+                    digest,
+                },
+            );
+        }
+
+        tracing::debug!(path = ?self.path, size = self.size, "Stored file in cache");
+        let evicted = self.cache.evict();
+        if !evicted.is_empty() {
+            let removed = tokio::task::spawn_blocking(move || {
+                for path in evicted {
+                    remove_evicted(&path);
+                }
+            });
+            if let Err(e) = removed.await {
+                tracing::error!(?e, "Removing evicted files from cache");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if !self.cache.inner.index.lock().unwrap().in_progress.contains(&self.path) {
+            return;
+        }
+
+        // The writer was not finished successfully, so the partial file needs to be removed,
+        // without blocking the runtime if there is one. The file stays in progress until then, so
+        // no other writer starts writing the same partial file
+        let cache = self.cache.clone();
+        let path = self.path.clone();
+        let temp_path = self.temp_path.clone();
+        let remove = move || {
+            if let Err(e) = std::fs::remove_file(&temp_path) {
+                tracing::warn!(?e, path = ?temp_path, "Removing partial cache file");
+            }
+            cache.inner.index.lock().unwrap().in_progress.remove(&path);
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(remove);
+            }
+            Err(_) => remove(),
+        };
+    }
+}
+
+/// Verifies the content against the strongest digest provided by the upstream index
+enum Verifier {
+    None,
+    Sha256(sha2::Sha256, String),
+    Sha384(sha2::Sha384, String),
+    Sha512(sha2::Sha512, String),
+}
+
+impl Verifier {
+    fn new(expected: &FileHashes) -> Self {
+        if let Some(digest) = expected.get("sha256") {
+            Self::Sha256(sha2::Sha256::new(), digest.clone())
+        } else if let Some(digest) = expected.get("sha384") {
+            Self::Sha384(sha2::Sha384::new(), digest.clone())
+        } else if let Some(digest) = expected.get("sha512") {
+            Self::Sha512(sha2::Sha512::new(), digest.clone())
+        } else {
+            Self::None
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        match self {
+            Self::None => {}
+            Self::Sha256(hasher, _) => hasher.update(chunk),
+            Self::Sha384(hasher, _) => hasher.update(chunk),
+            Self::Sha512(hasher, _) => hasher.update(chunk),
+        };
+    }
+
+    /// Compares a digest computed earlier against the expected one, `None` if it was computed
+    /// using a different algorithm
+    fn check(&self, digest: &FileDigest) -> Option<bool> {
+        let (algorithm, expected) = match self {
+            Self::None => return Some(true),
+            Self::Sha256(_, expected) => ("sha256", expected),
+            Self::Sha384(_, expected) => ("sha384", expected),
+            Self::Sha512(_, expected) => ("sha512", expected),
+        };
+
+        (digest.algorithm == algorithm).then(|| digest.value.eq_ignore_ascii_case(expected))
+    }
+
+    /// Returns the verified digest, `None` if there was nothing to verify against
+    fn verify(self) -> Result<Option<FileDigest>, CacheError> {
+        let (algorithm, actual, expected) = match self {
+            Self::None => return Ok(None),
+            Self::Sha256(hasher, expected) => ("sha256", format!("{:x}", hasher.finalize()), expected),
+            Self::Sha384(hasher, expected) => ("sha384", format!("{:x}", hasher.finalize()), expected),
+            Self::Sha512(hasher, expected) => ("sha512", format!("{:x}", hasher.finalize()), expected),
+        };
+
+        if !actual.eq_ignore_ascii_case(&expected) {
+            return Err(CacheError::DigestMismatch {
+                algorithm,
+                expected,
+                actual,
+            });
+        }
+
+        Ok(Some(FileDigest {
+            algorithm,
+            value: actual,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("cypi-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn sha256(content: &[u8]) -> FileHashes {
+        FileHashes::from([("sha256".to_string(), format!("{:x}", sha2::Sha256::digest(content)))])
+    }
+
+    async fn store(cache: &FileCache, package: &PackageName, filename: &str, content: &[u8]) {
+        let mut writer = cache
+            .writer(package, filename, &sha256(content))
+            .await
+            .unwrap()
+            .unwrap();
+        writer.write(content).await.unwrap();
+        writer.finish().await.unwrap();
+    }
+
+    #[test]
+    fn hits_are_verified_against_the_expected_digest() {
+        let dir = temp_dir("verify");
+        let package = PackageName::new("numpy");
+
+        runtime().block_on(async {
+            let cache = FileCache::open(&dir, 1024).unwrap();
+            store(&cache, &package, "numpy-1.0.tar.gz", b"first").await;
+
+            assert!(cache.get(&package, "numpy-1.0.tar.gz", &sha256(b"first")).await.is_some());
+            assert!(cache.get(&package, "numpy-1.0.tar.gz", &FileHashes::new()).await.is_some());
+
+            // The upstream file changed, so the cached one is outdated
+            assert!(cache.get(&package, "numpy-1.0.tar.gz", &sha256(b"second")).await.is_none());
+            assert!(!dir.join("numpy").join("numpy-1.0.tar.gz").exists());
+            assert!(cache.get(&package, "numpy-1.0.tar.gz", &sha256(b"first")).await.is_none());
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_picked_up_after_a_restart_are_verified() {
+        let dir = temp_dir("restart");
+        let package = PackageName::new("numpy");
+
+        runtime().block_on(async {
+            let cache = FileCache::open(&dir, 1024).unwrap();
+            store(&cache, &package, "numpy-1.0.tar.gz", b"first").await;
+            store(&cache, &package, "numpy-2.0.tar.gz", b"second").await;
+
+            let cache = FileCache::open(&dir, 1024).unwrap();
+            assert!(cache.get(&package, "numpy-1.0.tar.gz", &sha256(b"first")).await.is_some());
+            assert!(cache.get(&package, "numpy-2.0.tar.gz", &sha256(b"other")).await.is_none());
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_files_are_evicted() {
+        let dir = temp_dir("evict");
+        let package = PackageName::new("numpy");
+
+        runtime().block_on(async {
+            let cache = FileCache::open(&dir, 10).unwrap();
+            store(&cache, &package, "numpy-1.0.tar.gz", b"12345").await;
+            store(&cache, &package, "numpy-2.0.tar.gz", b"67890").await;
+            assert!(cache.get(&package, "numpy-1.0.tar.gz", &sha256(b"12345")).await.is_some());
+
+            store(&cache, &package, "numpy-3.0.tar.gz", b"abcde").await;
+            assert!(dir.join("numpy").join("numpy-1.0.tar.gz").exists());
+            assert!(!dir.join("numpy").join("numpy-2.0.tar.gz").exists());
+            assert!(cache.get(&package, "numpy-2.0.tar.gz", &sha256(b"67890")).await.is_none());
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
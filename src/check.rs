@@ -0,0 +1,109 @@
+//! Checks the configs without starting the server, so invalid configs can be rejected before they
+//! are rolled out
+
+use crate::{RemotePackageAuth, background::packages::resolve_index_auth, config, vault};
+
+/// Loads the configs the same way the background loaders do, printing every problem found.
+///
+/// With `probe`, every index is requested (using its credentials) and every package folder is
+/// read as well. Returns whether the configs are fine
+pub fn check_config(
+    customer_config: &std::path::Path,
+    package_config: &std::path::Path,
+    probe: bool,
+    vault_client: vault::VaultClient,
+) -> bool {
+    let mut problems = 0;
+
+    let packages = match config::PackageConfiguration::load(package_config) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            problems += report(&e);
+            None
+        }
+    };
+
+    if let Err(e) = config::CustomerConfig::load(customer_config, packages.as_ref()) {
+        problems += report(&e);
+    }
+
+    if let (true, Some(packages)) = (probe, packages.as_ref()) {
+        let http_client = crate::upstream_blocking_client();
+
+        let mut indexes: Vec<_> = packages.index.iter().collect();
+        indexes.sort_by_key(|(name, _)| *name);
+        for (name, index) in indexes {
+            if let Err(e) = probe_index(&http_client, &vault_client, index) {
+                println!("{}: index '{}': {}", package_config.display(), name, e);
+                problems += 1;
+            }
+        }
+
+        let mut folders: Vec<String> = packages
+            .package
+            .values()
+            .flat_map(|package| package.sources())
+            .filter_map(|source| match source {
+                config::PackageSource::Folder(folder) => Some(folder),
+                config::PackageSource::Index(_) => None,
+            })
+            .collect();
+        folders.sort();
+        folders.dedup();
+        for folder in folders {
+            if let Err(e) = std::fs::read_dir(&folder) {
+                println!("{}: folder '{}': {}", package_config.display(), folder, e);
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        println!("Found {} problem(s)", problems);
+        return false;
+    }
+
+    println!("Configs are valid");
+    true
+}
+
+/// Prints the error, listing every validation error on its own line. Returns the number of
+/// problems printed
+fn report(error: &config::LoadConfigError) -> usize {
+    match error {
+        config::LoadConfigError::Invalid { path, errors } => {
+            for e in errors {
+                println!("{}: {}", path.display(), e);
+            }
+            errors.len()
+        }
+        other => {
+            println!("{}", other);
+            1
+        }
+    }
+}
+
+/// Requests the root page of the index
+fn probe_index(
+    http_client: &reqwest::blocking::Client,
+    vault_client: &vault::VaultClient,
+    index: &config::IndexConfigEntry,
+) -> Result<(), String> {
+    let auth = match &index.auth {
+        Some(auth) => resolve_index_auth(auth, vault_client)
+            .map_err(|e| format!("resolving credentials: {:?}", e))?,
+        None => RemotePackageAuth::Unauthorized,
+    };
+
+    let url = reqwest::Url::parse(&index.url).map_err(|e| format!("invalid url '{}': {}", index.url, e))?;
+    let response = auth
+        .send_blocking(url, |url| http_client.get(url))
+        .map_err(|e| format!("requesting '{}': {}", index.url, e))?;
+
+    if !response.status().is_success() {
+        return Err(format!("'{}' responded with {}", index.url, response.status()));
+    }
+
+    Ok(())
+}
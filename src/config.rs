@@ -1,36 +1,798 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{PackageName, version::VersionSpecifiers};
 
 #[derive(Debug, serde::Deserialize)]
+#[serde(from = "RawPackageConfiguration")]
 pub struct PackageConfiguration {
     pub index: HashMap<String, IndexConfigEntry>,
-    pub package: HashMap<String, PackageConfigEntry>,
+    pub package: HashMap<PackageName, PackageConfigEntry>,
+    /// The package names which normalize to the same package
+    duplicates: DuplicateNames,
+}
+
+/// The package config as written, before the package names are normalized
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPackageConfiguration {
+    #[serde(default)]
+    index: HashMap<String, IndexConfigEntry>,
+    package: HashMap<String, PackageConfigEntry>,
+}
+
+impl From<RawPackageConfiguration> for PackageConfiguration {
+    fn from(raw: RawPackageConfiguration) -> Self {
+        // Sorted, so the same entry is kept for duplicates on every load
+        let mut entries: Vec<_> = raw.package.into_iter().collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut package = NormalizedTable::default();
+        for (name, entry) in entries {
+            package.insert(name, entry);
+        }
+        let (package, duplicates) = package.finish();
+
+        Self {
+            index: raw.index,
+            package,
+            duplicates,
+        }
+    }
+}
+
+/// The raw names used for the same package in a table keyed by package names, like
+/// `Scikit_Learn` and `scikit-learn`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DuplicateNames(BTreeMap<PackageName, BTreeSet<String>>);
+
+impl DuplicateNames {
+    pub fn iter(&self) -> impl Iterator<Item = (&PackageName, Vec<String>)> {
+        self.0
+            .iter()
+            .map(|(pname, names)| (pname, names.iter().cloned().collect()))
+    }
+}
+
+/// Normalizes the keys of a table keyed by package names, only keeping the first entry if
+/// multiple names normalize to the same package
+struct NormalizedTable<V> {
+    entries: HashMap<PackageName, (String, V)>,
+    duplicates: DuplicateNames,
+}
+
+impl<V> Default for NormalizedTable<V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            duplicates: DuplicateNames::default(),
+        }
+    }
+}
+
+impl<V> NormalizedTable<V> {
+    fn insert(&mut self, raw: String, value: V) {
+        let pname = PackageName::new(&raw);
+        match self.entries.get(&pname) {
+            Some((first, _)) => {
+                let names = self
+                    .duplicates
+                    .0
+                    .entry(pname)
+                    .or_insert_with(|| BTreeSet::from([first.clone()]));
+                names.insert(raw);
+            }
+            None => {
+                self.entries.insert(pname, (raw, value));
+            }
+        };
+    }
+
+    fn finish(self) -> (HashMap<PackageName, V>, DuplicateNames) {
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|(pname, (_, value))| (pname, value))
+            .collect();
+        (entries, self.duplicates)
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct IndexConfigEntry {
     pub url: String,
+    /// The credentials for the index, these are only ever sent to the origin of the index itself
+    pub auth: Option<IndexAuth>,
+}
+
+/// The different ways of authenticating against an upstream index
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum IndexAuth {
+    Basic {
+        username: SecretValue,
+        password: Option<SecretValue>,
+    },
+    Bearer {
+        token: SecretValue,
+    },
+    /// An arbitrary header, like `PRIVATE-TOKEN` for GitLab or `X-JFrog-Art-Api` for Artifactory
+    Header {
+        name: String,
+        value: SecretValue,
+    },
+}
+
+/// A credential, either given directly or loaded from the environment or Vault
+///
+/// ```toml
+/// token = "plain"
+/// token = { env = "INDEX_TOKEN" }
+/// token = { vault = { path = "indexes/gitlab", key = "token" } }
+/// ```
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum SecretValue {
+    Plain(String),
+    Env { env: String },
+    Vault { vault: VaultSecret },
 }
 
 #[derive(Debug, serde::Deserialize)]
+pub struct VaultSecret {
+    pub path: String,
+    pub key: String,
+}
+
+impl std::fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain(_) => f.write_str("Plain(<redacted>)"),
+            Self::Env { env } => f.debug_struct("Env").field("env", env).finish(),
+            Self::Vault { vault } => f.debug_struct("Vault").field("vault", vault).finish(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PackageConfigEntry {
     pub index: Option<String>,
     pub folder: Option<String>,
+    /// Additional sources of the package, merged with the `index` and `folder`
+    #[serde(default)]
+    pub sources: Vec<PackageSourceEntry>,
+}
+
+/// A single source of a package, if a file is provided by multiple sources, the one from the
+/// source with the highest priority is used
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackageSourceEntry {
+    pub index: Option<String>,
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub priority: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PackageSource {
+    Index(String),
+    Folder(String),
+}
+
+impl PackageConfigEntry {
+    /// All the sources of the package, ordered by their priority.
+    ///
+    /// The `index` and `folder` of the package itself have priority `0`, with the folder taking
+    /// precedence over the index. Sources with the same priority keep the order of the config
+    pub fn sources(&self) -> Vec<PackageSource> {
+        let legacy = [
+            self.folder.clone().map(PackageSource::Folder),
+            self.index.clone().map(PackageSource::Index),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|source| (0, source));
+
+        let listed = self.sources.iter().flat_map(|entry| {
+            [
+                entry.folder.clone().map(PackageSource::Folder),
+                entry.index.clone().map(PackageSource::Index),
+            ]
+            .into_iter()
+            .flatten()
+            .map(|source| (entry.priority, source))
+        });
+
+        let mut sources: Vec<(i64, PackageSource)> = legacy.chain(listed).collect();
+        sources.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+
+        sources.into_iter().map(|(_, source)| source).collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadConfigError {
+    Reading {
+        path: std::path::PathBuf,
+        error: std::io::Error,
+    },
+    Parsing {
+        path: std::path::PathBuf,
+        line: Option<usize>,
+        column: Option<usize>,
+        /// The path of the offending key, like `package.numpy.index`
+        key: Option<String>,
+        message: String,
+    },
+    Invalid {
+        path: std::path::PathBuf,
+        errors: Vec<ValidationError>,
+    },
+}
+
+/// A config which is valid TOML, but doesn't make sense
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    UnknownIndex { package: PackageName, index: String },
+    InvalidIndexUrl { index: String, url: String },
+    MissingSource(PackageName),
+    /// An entry of the package's `sources` (by its position) with neither an index nor a folder
+    EmptySource { package: PackageName, position: usize },
+    UnknownBundle { customer: String, bundle: String },
+    /// A customer (or bundle) references a package, which is not configured
+    UnknownPackage { customer: String, package: PackageName },
+    /// Multiple names for the same package, only one of them is used. The customer is `None` for
+    /// the packages in the package config
+    DuplicatePackage {
+        customer: Option<String>,
+        package: PackageName,
+        names: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for LoadConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reading { path, error } => write!(f, "{}: {}", path.display(), error),
+            Self::Parsing {
+                path,
+                line,
+                column,
+                key,
+                message,
+            } => {
+                write!(f, "{}", path.display())?;
+                if let (Some(line), Some(column)) = (line, column) {
+                    write!(f, ":{}:{}", line, column)?;
+                }
+                write!(f, ": {}", message.trim())?;
+                if let Some(key) = key {
+                    write!(f, " (at `{}`)", key)?;
+                }
+                Ok(())
+            }
+            Self::Invalid { path, errors } => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}: {}", path.display(), errors.join("; "))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownIndex { package, index } => {
+                write!(f, "package '{}' uses unknown index '{}'", package, index)
+            }
+            Self::InvalidIndexUrl { index, url } => {
+                write!(f, "index '{}' has invalid url '{}'", index, url)
+            }
+            Self::MissingSource(package) => {
+                write!(f, "package '{}' has neither an index nor a folder", package)
+            }
+            Self::EmptySource { package, position } => write!(
+                f,
+                "package '{}' has a source with neither an index nor a folder (at `package.{}.sources[{}]`)",
+                package,
+                package,
+                position
+            ),
+            Self::UnknownBundle { customer, bundle } => {
+                write!(f, "customer '{}' uses unknown bundle '{}'", customer, bundle)
+            }
+            Self::UnknownPackage { customer, package } => {
+                write!(f, "'{}' references unknown package '{}'", customer, package)
+            }
+            Self::DuplicatePackage {
+                customer,
+                package,
+                names,
+            } => {
+                match customer {
+                    Some(customer) => write!(f, "'{}' lists", customer)?,
+                    None => write!(f, "config lists")?,
+                };
+                write!(
+                    f,
+                    " package '{}' multiple times, as '{}'",
+                    package,
+                    names.join("', '")
+                )
+            }
+        }
+    }
+}
+
+/// Reads and deserializes the TOML file, keeping track of where deserializing failed
+pub(crate) fn load_toml<T>(path: &std::path::Path) -> Result<T, LoadConfigError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let content = std::fs::read_to_string(path).map_err(|error| LoadConfigError::Reading {
+        path: path.to_path_buf(),
+        error,
+    })?;
+
+    parse_toml(path, &content)
+}
+
+/// Deserializes the content of the TOML file at the path
+fn parse_toml<T>(path: &std::path::Path, content: &str) -> Result<T, LoadConfigError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    serde_path_to_error::deserialize(toml::Deserializer::new(content)).map_err(|e| {
+        let key = e.path().to_string();
+        let inner = e.into_inner();
+        // Some errors only point at the whole document, which is not helpful
+        let (line, column) = inner
+            .span()
+            .filter(|span| *span != (0..content.len()))
+            .map(|span| line_column(content, span.start))
+            .unzip();
+
+        LoadConfigError::Parsing {
+            path: path.to_path_buf(),
+            line,
+            column,
+            key: (key != ".").then_some(key),
+            message: inner.message().to_string(),
+        }
+    })
+}
+
+/// The 1-based line and column of the byte offset in the content
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line_start = before.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+fn validated<T>(path: &std::path::Path, config: T, mut errors: Vec<ValidationError>) -> Result<T, LoadConfigError> {
+    if errors.is_empty() {
+        return Ok(config);
+    }
+
+    // The configs are hash maps, so sort the errors to keep them stable between reloads
+    errors.sort_by_key(|e| e.to_string());
+    Err(LoadConfigError::Invalid {
+        path: path.to_path_buf(),
+        errors,
+    })
 }
 
 impl PackageConfiguration {
-    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, ()> {
-        let content = std::fs::read_to_string(path).map_err(|e| ())?;
-        toml::from_str(&content).map_err(|e| ())
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, LoadConfigError> {
+        let path = path.as_ref();
+        let config: Self = load_toml(path)?;
+        let errors = config.validate();
+        validated(path, config, errors)
+    }
+
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+
+        for (name, index) in self.index.iter() {
+            if reqwest::Url::parse(&index.url).is_err() {
+                errors.push(ValidationError::InvalidIndexUrl {
+                    index: name.clone(),
+                    url: index.url.clone(),
+                });
+            }
+        }
+
+        for (package, names) in self.duplicates.iter() {
+            errors.push(ValidationError::DuplicatePackage {
+                customer: None,
+                package: package.clone(),
+                names,
+            });
+        }
+
+        for (pname, package) in self.package.iter() {
+            for (position, entry) in package.sources.iter().enumerate() {
+                if entry.index.is_none() && entry.folder.is_none() {
+                    errors.push(ValidationError::EmptySource {
+                        package: pname.clone(),
+                        position,
+                    });
+                }
+            }
+
+            let sources = package.sources();
+            if sources.is_empty() {
+                errors.push(ValidationError::MissingSource(pname.clone()));
+            }
+
+            for source in sources {
+                match source {
+                    PackageSource::Index(index) if !self.index.contains_key(&index) => {
+                        errors.push(ValidationError::UnknownIndex {
+                            package: pname.clone(),
+                            index,
+                        });
+                    }
+                    _ => {}
+                };
+            }
+        }
+
+        errors
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
+/// The customers, keyed by their name
+#[derive(Debug)]
 pub struct CustomerConfig {
-    #[serde(flatten)]
+    /// Named sets of packages, which can be shared by multiple customers. As they are stored in
+    /// the `[bundle]` table, no customer can be named `bundle`
+    pub bundle: HashMap<String, ConfigBundle>,
     pub customers: HashMap<String, ConfigCustomer>,
 }
 
+impl CustomerConfig {
+    /// Loads the customers, validating the referenced packages against the package configuration
+    /// if it is available. Any invalid entry rejects the whole config, so the previous one stays
+    /// in use instead of a customer silently losing access
+    pub fn load(
+        path: impl AsRef<std::path::Path>,
+        packages: Option<&PackageConfiguration>,
+    ) -> Result<Self, LoadConfigError> {
+        let path = path.as_ref();
+        let config: Self = load_toml(path)?;
+        let errors = config.validate(packages);
+        validated(path, config, errors)
+    }
+
+    /// Checks the referenced packages and bundles. For bundles, the customer of the errors is
+    /// `bundle.{name}`
+    pub fn validate(&self, packages: Option<&PackageConfiguration>) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+
+        let mut check_packages = |name: String, customer_packages: &CustomerPackages| {
+            for (package, names) in customer_packages.1.iter() {
+                errors.push(ValidationError::DuplicatePackage {
+                    customer: Some(name.clone()),
+                    package: package.clone(),
+                    names,
+                });
+            }
+
+            let packages = match packages {
+                Some(p) => p,
+                None => return,
+            };
+
+            for pname in customer_packages.0.keys() {
+                if !packages.package.contains_key(pname) {
+                    errors.push(ValidationError::UnknownPackage {
+                        customer: name.clone(),
+                        package: pname.clone(),
+                    });
+                }
+            }
+        };
+
+        for (name, bundle) in self.bundle.iter() {
+            check_packages(format!("bundle.{}", name), &bundle.packages);
+        }
+        for (name, customer) in self.customers.iter() {
+            check_packages(name.clone(), &customer.packages);
+        }
+
+        for (name, customer) in self.customers.iter() {
+            for bundle in customer.bundles.iter() {
+                if !self.bundle.contains_key(bundle) {
+                    errors.push(ValidationError::UnknownBundle {
+                        customer: name.clone(),
+                        bundle: bundle.clone(),
+                    });
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for CustomerConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = CustomerConfig;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a table of customers")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut bundle = HashMap::new();
+                let mut customers = HashMap::new();
+
+                // Deserializing every table on its own (instead of flattening the customers)
+                // keeps the location of errors
+                while let Some(name) = map.next_key::<String>()? {
+                    if name == "bundle" {
+                        bundle = map.next_value::<Bundles>()?.0;
+                    } else {
+                        let customer = map.next_value::<ConfigCustomer>()?;
+                        customers.insert(name, customer);
+                    }
+                }
+
+                Ok(CustomerConfig { bundle, customers })
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigBundle {
+    pub packages: CustomerPackages,
+}
+
+/// The `[bundle]` table of the customer config
+struct Bundles(HashMap<String, ConfigBundle>);
+
+impl<'de> serde::Deserialize<'de> for Bundles {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserialize_bundles(deserializer).map(Self)
+    }
+}
+
+/// Deserializes the bundles, explaining the error if the `[bundle]` table was meant to be a
+/// customer instead, which would have plain values (like `packages = [...]`) instead of tables
+fn deserialize_bundles<'de, D>(deserializer: D) -> Result<HashMap<String, ConfigBundle>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct BundleEntry(ConfigBundle);
+
+    impl<'de> serde::Deserialize<'de> for BundleEntry {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            struct Visitor;
+
+            impl<'de> serde::de::Visitor<'de> for Visitor {
+                type Value = BundleEntry;
+
+                fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    formatter.write_str(
+                        "a bundle table (`[bundle]` only contains bundles, a customer can't be named `bundle`)",
+                    )
+                }
+
+                fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+                where
+                    A: serde::de::MapAccess<'de>,
+                {
+                    serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                        .map(BundleEntry)
+                }
+            }
+
+            deserializer.deserialize_map(Visitor)
+        }
+    }
+
+    let bundles: HashMap<String, BundleEntry> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(bundles
+        .into_iter()
+        .map(|(name, bundle)| (name, bundle.0))
+        .collect())
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigCustomer {
-    pub packages: Vec<String>,
+    /// The bundles the customer has access to, in addition to its own packages
+    #[serde(default)]
+    pub bundles: Vec<String>,
+    #[serde(default)]
+    pub packages: CustomerPackages,
+    /// Also expose all the configured projects the customer's packages depend on
+    #[serde(default)]
+    pub include_dependencies: bool,
+    /// Glob patterns of the wheel platform tags the customer can install (e.g. `manylinux*_x86_64`)
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    /// Glob patterns of the wheel Python tags the customer can install (e.g. `cp311`, `py3`)
+    #[serde(default)]
+    pub python: Vec<String>,
+}
+
+/// The packages a customer has access to, along with the versions they are allowed to see.
+///
+/// Either a list of package names, giving access to all the versions, or a table of package names
+/// to version specifiers
+/// ```toml
+/// packages = ["numpy", "tensorflow"]
+///
+/// [customer.packages]
+/// numpy = "*"
+/// tensorflow = ">=2.12,<2.15"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CustomerPackages(
+    pub HashMap<PackageName, VersionSpecifiers>,
+    /// The package names listed more than once
+    pub DuplicateNames,
+);
+
+impl<'de> serde::Deserialize<'de> for CustomerPackages {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = CustomerPackages;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a list of package names or a table of version specifiers")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let mut packages = NormalizedTable::default();
+                while let Some(name) = seq.next_element::<String>()? {
+                    packages.insert(name, VersionSpecifiers::default());
+                }
+                let (packages, duplicates) = packages.finish();
+                Ok(CustomerPackages(packages, duplicates))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut packages = NormalizedTable::default();
+                while let Some((name, specifiers)) = map.next_entry::<String, VersionSpecifiers>()? {
+                    packages.insert(name, specifiers);
+                }
+                let (packages, duplicates) = packages.finish();
+                Ok(CustomerPackages(packages, duplicates))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse<T: serde::de::DeserializeOwned>(content: &str) -> Result<T, LoadConfigError> {
+        parse_toml(std::path::Path::new("test.toml"), content)
+    }
+
+    #[test]
+    fn customer_errors_point_at_the_customer() {
+        let content = r#"
+[acme]
+packages = { numpy = "*" }
+
+[globex]
+include_dependencies = "yes"
+"#;
+
+        match parse::<CustomerConfig>(content) {
+            Err(LoadConfigError::Parsing { line, key, .. }) => {
+                assert_eq!(line, Some(6));
+                assert_eq!(key.as_deref(), Some("globex.include_dependencies"));
+            }
+            other => panic!("expected a parsing error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_sources_are_reported() {
+        let packages: PackageConfiguration = parse(
+            r#"
+[package.numpy]
+sources = [{ folder = "packages/" }, { priority = 1 }]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            packages.validate(),
+            vec![ValidationError::EmptySource {
+                package: PackageName::new("numpy"),
+                position: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_customer_entries_reject_the_config() {
+        let dir = std::env::temp_dir().join(format!("cypi-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("customers.toml");
+        std::fs::write(
+            &path,
+            r#"
+[acme]
+packages = { numpy = "*", unknown = "*" }
+bundles = ["missing"]
+
+[globex]
+packages = { numpy = ">=2" }
+"#,
+        )
+        .unwrap();
+        let packages: PackageConfiguration = parse(
+            r#"
+[package.numpy]
+folder = "packages/"
+"#,
+        )
+        .unwrap();
+
+        let result = CustomerConfig::load(&path, Some(&packages));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        match result {
+            Err(LoadConfigError::Invalid { errors, .. }) => assert_eq!(
+                errors,
+                vec![
+                    ValidationError::UnknownPackage {
+                        customer: "acme".to_string(),
+                        package: PackageName::new("unknown"),
+                    },
+                    ValidationError::UnknownBundle {
+                        customer: "acme".to_string(),
+                        bundle: "missing".to_string(),
+                    },
+                ]
+            ),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,410 @@
+//! Parsing the filenames of distributions (wheels and sdists)
+
+use crate::{PackageName, version::Version};
+
+/// The extensions of source distributions we recognize.
+///
+/// PEP 625 only allows `.tar.gz`, but older sdists were also published using other formats
+pub const SDIST_EXTENSIONS: &[&str] = &[".tar.gz", ".zip", ".tar.bz2", ".tar.xz", ".tgz"];
+
+/// The parsed filename of a distribution
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistributionFilename {
+    pub name: PackageName,
+    pub version: Version,
+    pub kind: DistributionKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DistributionKind {
+    /// A built distribution (`.whl`)
+    Wheel(WheelTags),
+    /// A source distribution (`.tar.gz`, `.zip`, ...)
+    SourceDist,
+}
+
+/// The tags of a wheel, as described in the binary distribution format and PEP 425.
+///
+/// Each of the tags can be a compressed tag set (e.g. `py2.py3`), which is stored expanded
+#[derive(Debug, Clone, PartialEq)]
+pub struct WheelTags {
+    pub build: Option<BuildTag>,
+    pub python: Vec<String>,
+    pub abi: Vec<String>,
+    pub platform: Vec<String>,
+}
+
+/// The optional build tag of a wheel, which starts with a number used for sorting
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BuildTag {
+    pub number: u64,
+    pub suffix: String,
+}
+
+impl DistributionFilename {
+    /// Parses the filename of a wheel or source distribution, returns `None` if the filename is
+    /// not a distribution we understand
+    pub fn parse(filename: &str) -> Option<Self> {
+        if let Some(stem) = filename.strip_suffix(".whl") {
+            return Self::parse_wheel(stem);
+        }
+
+        SDIST_EXTENSIONS
+            .iter()
+            .find_map(|ext| filename.strip_suffix(ext))
+            .and_then(Self::parse_sdist)
+    }
+
+    /// Parses `{name}-{version}(-{build tag})?-{python tag}-{abi tag}-{platform tag}`
+    fn parse_wheel(stem: &str) -> Option<Self> {
+        let parts: Vec<&str> = stem.split('-').collect();
+        if parts.iter().any(|p| p.is_empty()) {
+            return None;
+        }
+
+        let (name, version, build, tags) = match parts.as_slice() {
+            [name, version, python, abi, platform] => {
+                (name, version, None, [python, abi, platform])
+            }
+            [name, version, build, python, abi, platform] => {
+                (name, version, Some(BuildTag::parse(build)?), [python, abi, platform])
+            }
+            _ => return None,
+        };
+
+        let [python, abi, platform] = tags.map(|tag| tag.split('.').map(str::to_string).collect());
+
+        Some(Self {
+            name: PackageName::new(name),
+            version: Version::parse(version).ok()?,
+            kind: DistributionKind::Wheel(WheelTags {
+                build,
+                python,
+                abi,
+                platform,
+            }),
+        })
+    }
+
+    /// Parses `{name}-{version}`.
+    ///
+    /// PEP 625 requires the name to be normalized with `_` instead of `-`, but legacy sdists may
+    /// contain `-` in their name, so the version is everything after the last `-`
+    fn parse_sdist(stem: &str) -> Option<Self> {
+        let (name, version) = stem.rsplit_once('-')?;
+        if name.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            name: PackageName::new(name),
+            version: Version::parse(version).ok()?,
+            kind: DistributionKind::SourceDist,
+        })
+    }
+
+    /// The tags of the distribution, if it is a wheel
+    pub fn wheel_tags(&self) -> Option<&WheelTags> {
+        match &self.kind {
+            DistributionKind::Wheel(tags) => Some(tags),
+            DistributionKind::SourceDist => None,
+        }
+    }
+}
+
+impl BuildTag {
+    fn parse(raw: &str) -> Option<Self> {
+        let len = raw
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(raw.len());
+
+        Some(Self {
+            number: raw[..len].parse().ok()?,
+            suffix: raw[len..].to_string(),
+        })
+    }
+}
+
+/// Limits the visible wheels to the ones with matching platform and Python tags.
+///
+/// The patterns are globs (e.g. `manylinux*_x86_64`, `cp3*`), an empty list allows every tag.
+/// Source distributions and wheels for the `any` platform are never filtered out, as they can be
+/// installed everywhere. Likewise wheels with a generic Python tag (e.g. `py3`, `py2.py3`) are
+/// allowed by every pattern for the same major Python version, like `cp311`, as long as the tag
+/// doesn't ask for a newer minor version. Wheels built for the stable ABI (`abi3`) are allowed by
+/// CPython patterns of the same or a newer minor version, so `cp311` allows `cp39-abi3` wheels
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WheelFilter {
+    pub platforms: Vec<String>,
+    pub python: Vec<String>,
+}
+
+impl WheelFilter {
+    /// Whether the filter allows every distribution
+    pub fn is_empty(&self) -> bool {
+        self.platforms.is_empty() && self.python.is_empty()
+    }
+
+    pub fn allows(&self, distribution: &DistributionFilename) -> bool {
+        let tags = match distribution.wheel_tags() {
+            Some(t) => t,
+            None => return true,
+        };
+
+        let matches = |patterns: &[String], tags: &[String], compatible: &dyn Fn(&str, &str) -> bool| {
+            patterns.is_empty()
+                || tags.iter().any(|tag| {
+                    patterns
+                        .iter()
+                        .any(|pattern| glob_matches(pattern, tag) || compatible(pattern, tag))
+                })
+        };
+
+        let stable_abi = tags.abi.iter().any(|abi| abi == "abi3");
+        let python_compatible = |pattern: &str, tag: &str| {
+            generic_python_compatible(pattern, tag) || (stable_abi && stable_abi_compatible(pattern, tag))
+        };
+
+        let platform = tags.platform.iter().any(|tag| tag == "any")
+            || matches(&self.platforms, &tags.platform, &|_, _| false);
+        platform && matches(&self.python, &tags.python, &python_compatible)
+    }
+}
+
+/// Whether the tag is a generic Python tag (like `py3` or `py310`), which can be installed by the
+/// interpreter the pattern (like `cp311` or `cp3*`) is for
+fn generic_python_compatible(pattern: &str, tag: &str) -> bool {
+    let tag_version = match tag.strip_prefix("py") {
+        Some(v) if !v.is_empty() && v.chars().all(|c| c.is_ascii_digit()) => v,
+        _ => return false,
+    };
+    let pattern_version = pattern.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+
+    version_compatible(pattern_version, tag_version)
+}
+
+/// Whether the CPython tag of a stable ABI wheel (like `cp39`) can be installed by the CPython
+/// interpreter the pattern (like `cp311`) is for
+fn stable_abi_compatible(pattern: &str, tag: &str) -> bool {
+    match (pattern.strip_prefix("cp"), tag.strip_prefix("cp")) {
+        (Some(pattern_version), Some(tag_version)) => {
+            tag_version.chars().all(|c| c.is_ascii_digit()) && version_compatible(pattern_version, tag_version)
+        }
+        _ => false,
+    }
+}
+
+/// Whether the Python version of a tag (like `39` of `cp39`) is compatible with the one of a
+/// pattern (like `311` of `cp311`), having the same major and at most the same minor version. A
+/// pattern without a plain minor version (like `3*`) allows every minor version
+fn version_compatible(pattern_version: &str, tag_version: &str) -> bool {
+    let split = |version: &str| {
+        let major = version.chars().next().filter(|c| c.is_ascii_digit())?;
+        Some((major, version[1..].parse::<u32>().ok()))
+    };
+
+    match (split(pattern_version), split(tag_version)) {
+        (Some((pattern_major, pattern_minor)), Some((tag_major, tag_minor))) => {
+            pattern_major == tag_major
+                && match (pattern_minor, tag_minor) {
+                    (Some(pattern_minor), Some(tag_minor)) => tag_minor <= pattern_minor,
+                    _ => true,
+                }
+        }
+        _ => false,
+    }
+}
+
+/// Matches the value against a glob pattern supporting `*` and `?`, ignoring the case
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_ascii_lowercase().chars().collect();
+    let value: Vec<char> = value.to_ascii_lowercase().chars().collect();
+
+    let (mut p, mut v) = (0, 0);
+    // The position of the last `*` and the position in the value it was matched at, used to
+    // backtrack if the rest of the pattern does not match
+    let mut star: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_p, star_v)) => {
+                    p = star_p + 1;
+                    v = star_v + 1;
+                    star = Some((star_p, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(filename: &str) -> DistributionFilename {
+        DistributionFilename::parse(filename).unwrap()
+    }
+
+    fn filter(platforms: &[&str], python: &[&str]) -> WheelFilter {
+        WheelFilter {
+            platforms: platforms.iter().map(|p| p.to_string()).collect(),
+            python: python.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn wheel_tags_are_expanded() {
+        let wheel = parse("numpy-1.26.4-pp39.pp310-pypy_73-manylinux_2_17_x86_64.manylinux2014_x86_64.whl");
+
+        assert_eq!(wheel.name, PackageName::new("numpy"));
+        assert_eq!(wheel.version, Version::parse("1.26.4").unwrap());
+        assert_eq!(
+            wheel.wheel_tags(),
+            Some(&WheelTags {
+                build: None,
+                python: vec!["pp39".to_string(), "pp310".to_string()],
+                abi: vec!["pypy_73".to_string()],
+                platform: vec![
+                    "manylinux_2_17_x86_64".to_string(),
+                    "manylinux2014_x86_64".to_string()
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn build_tags() {
+        let wheel = parse("foo-1.0-2b-py3-none-any.whl");
+        let build = wheel.wheel_tags().unwrap().build.clone();
+        assert_eq!(
+            build,
+            Some(BuildTag {
+                number: 2,
+                suffix: "b".to_string()
+            })
+        );
+
+        // Build tags need to start with a number
+        assert_eq!(DistributionFilename::parse("foo-1.0-b2-py3-none-any.whl"), None);
+
+        assert!(BuildTag::parse("2").unwrap() < BuildTag::parse("10").unwrap());
+        assert!(BuildTag::parse("1").unwrap() < BuildTag::parse("1a").unwrap());
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        assert_eq!(parse("Foo_Bar-1.0-py3-none-any.whl").name, PackageName::new("foo-bar"));
+        assert_eq!(parse("foo.bar-1.0.tar.gz").name, PackageName::new("foo-bar"));
+        assert_eq!(parse("zope_interface-6.0.tar.gz").name, PackageName::new("zope-interface"));
+    }
+
+    #[test]
+    fn legacy_sdist_names_may_contain_dashes() {
+        let sdist = parse("foo-bar-1.0.post1.tar.gz");
+        assert_eq!(sdist.name, PackageName::new("foo-bar"));
+        assert_eq!(sdist.version, Version::parse("1.0.post1").unwrap());
+    }
+
+    #[test]
+    fn sdist_extensions() {
+        for filename in ["foo-1.0.tar.gz", "foo-1.0.zip", "foo-1.0.tar.bz2", "foo-1.0.tgz"] {
+            let sdist = parse(filename);
+            assert_eq!(sdist.kind, DistributionKind::SourceDist, "{}", filename);
+            assert_eq!(sdist.version, Version::parse("1.0").unwrap(), "{}", filename);
+        }
+
+        assert_eq!(DistributionFilename::parse("foo-1.0.tar"), None);
+        assert_eq!(DistributionFilename::parse("foo-1.0.exe"), None);
+        assert_eq!(DistributionFilename::parse("-1.0.tar.gz"), None);
+    }
+
+    #[test]
+    fn invalid_wheels() {
+        assert_eq!(DistributionFilename::parse("foo-1.0-py3-none.whl"), None);
+        assert_eq!(DistributionFilename::parse("foo-1.0--none-any.whl"), None);
+        assert_eq!(DistributionFilename::parse("foo-notaversion-py3-none-any.whl"), None);
+    }
+
+    #[test]
+    fn glob_backtracking() {
+        assert!(glob_matches("manylinux*_x86_64", "manylinux_2_17_x86_64"));
+        assert!(glob_matches("manylinux*_x86_64", "manylinux2014_x86_64"));
+        assert!(!glob_matches("manylinux*_x86_64", "manylinux2014_aarch64"));
+        assert!(glob_matches("*a*b", "aaab"));
+        assert!(glob_matches("*x86_64", "musllinux_1_1_x86_64_x86_64"));
+        assert!(!glob_matches("*a*b", "aaba"));
+        assert!(glob_matches("cp3?", "CP39"));
+        assert!(!glob_matches("cp3?", "cp310"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("cp3**", "cp3"));
+    }
+
+    #[test]
+    fn filter_platforms() {
+        let filter = filter(&["manylinux*_x86_64"], &[]);
+
+        assert!(filter.allows(&parse("foo-1.0-cp311-cp311-manylinux_2_17_x86_64.whl")));
+        assert!(!filter.allows(&parse("foo-1.0-cp311-cp311-win_amd64.whl")));
+        assert!(filter.allows(&parse("foo-1.0-py3-none-any.whl")));
+        assert!(filter.allows(&parse("foo-1.0.zip")));
+    }
+
+    #[test]
+    fn filter_python() {
+        let filter = filter(&[], &["cp311"]);
+
+        assert!(filter.allows(&parse("foo-1.0-cp311-cp311-win_amd64.whl")));
+        assert!(!filter.allows(&parse("foo-1.0-cp310-cp310-win_amd64.whl")));
+        assert!(filter.allows(&parse("foo-1.0.tar.gz")));
+    }
+
+    #[test]
+    fn filter_python_allows_generic_tags() {
+        let cp311 = filter(&["manylinux*_x86_64"], &["cp311"]);
+
+        assert!(cp311.allows(&parse("foo-1.0-py3-none-any.whl")));
+        assert!(cp311.allows(&parse("foo-1.0-py2.py3-none-any.whl")));
+        assert!(cp311.allows(&parse("foo-1.0-py310-none-any.whl")));
+        assert!(!cp311.allows(&parse("foo-1.0-py2-none-any.whl")));
+        // The platform still needs to match
+        assert!(!cp311.allows(&parse("foo-1.0-py3-none-win_amd64.whl")));
+
+        assert!(cp311.allows(&parse("foo-1.0-py3-none-manylinux_2_17_x86_64.whl")));
+        assert!(!filter(&[], &["cp3*"]).allows(&parse("foo-1.0-py2-none-any.whl")));
+        assert!(filter(&[], &["cp3*"]).allows(&parse("foo-1.0-py3-none-any.whl")));
+        assert!(!filter(&[], &["*27"]).allows(&parse("foo-1.0-py3-none-any.whl")));
+    }
+
+    #[test]
+    fn filter_python_compares_minor_versions() {
+        let cp311 = filter(&[], &["cp311"]);
+
+        assert!(cp311.allows(&parse("foo-1.0-py311-none-any.whl")));
+        assert!(!cp311.allows(&parse("foo-1.0-py312-none-any.whl")));
+        assert!(filter(&[], &["cp3*"]).allows(&parse("foo-1.0-py312-none-any.whl")));
+    }
+
+    #[test]
+    fn filter_python_allows_stable_abi() {
+        let cp311 = filter(&["manylinux*_x86_64"], &["cp311"]);
+
+        assert!(cp311.allows(&parse("cryptography-44.0.0-cp39-abi3-manylinux_2_28_x86_64.whl")));
+        assert!(cp311.allows(&parse("foo-1.0-cp311-abi3-manylinux_2_28_x86_64.whl")));
+        assert!(!cp311.allows(&parse("foo-1.0-cp312-abi3-manylinux_2_28_x86_64.whl")));
+        // Only wheels for the stable ABI work on newer versions
+        assert!(!cp311.allows(&parse("foo-1.0-cp39-cp39-manylinux_2_28_x86_64.whl")));
+        assert!(!filter(&[], &["pp310"]).allows(&parse("foo-1.0-cp39-abi3-manylinux_2_28_x86_64.whl")));
+    }
+}
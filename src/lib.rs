@@ -1,30 +1,195 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 pub mod api;
 pub mod auth;
 pub mod background;
+pub mod cache;
+pub mod check;
 pub mod config;
+pub mod distribution;
+pub mod metadata;
+pub mod name;
+pub mod tls;
+pub mod version;
+pub mod vault;
+
+pub use name::PackageName;
 
 #[derive(Debug, clap::Parser)]
+#[command(subcommand_negates_reqs = true)]
 pub struct CliArgs {
-    #[clap(long, default_value = "customers.toml")]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[clap(long, global = true, default_value = "customers.toml")]
     pub customer_config: std::path::PathBuf,
-    #[clap(long, default_value = "packages.toml")]
+    #[clap(long, global = true, default_value = "packages.toml")]
     pub package_config: std::path::PathBuf,
 
+    /// The address the server listens on
+    #[clap(long, default_value = "0.0.0.0")]
+    pub address: std::net::IpAddr,
+    /// The port the server listens on
+    #[clap(long, default_value_t = 3030)]
+    pub port: u16,
+    /// Listen on the unix socket at the path instead of the address and port, a stale socket (which
+    /// no server is listening on anymore) at the path is replaced
+    #[clap(long, conflicts_with_all = ["address", "port", "tls_cert"])]
+    pub unix_socket: Option<std::path::PathBuf>,
+    /// The PEM encoded certificate chain, the server terminates TLS itself if set. The
+    /// certificate and key are reloaded once their files change
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<std::path::PathBuf>,
+    /// The PEM encoded private key of the certificate
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<std::path::PathBuf>,
+    /// How often the certificate and key are reloaded in any case, in seconds, so a renewed
+    /// certificate is used even if the change was missed
+    #[clap(long, default_value_t = 3600)]
+    pub tls_refresh_interval: u64,
+
     /// The sqlite url to connect to 
     ///
     /// Examples
     /// * `sqlite::memory:` stores the data only in-memory
     /// * `sqlite://data.db` uses the data.db file (needs to exist before)
+    #[clap(long, required = true)]
+    pub sqlite_url: Option<String>,
+
+    /// The directory in which files downloaded from upstream indexes are cached, caching is
+    /// disabled if not set
+    #[clap(long)]
+    pub file_cache_dir: Option<std::path::PathBuf>,
+    /// The maximum size of the file cache in bytes, the least recently used files are evicted once
+    /// it is exceeded
+    #[clap(long, default_value_t = 10 * 1024 * 1024 * 1024)]
+    pub file_cache_max_size: u64,
+
+    /// The file in which the pages loaded from upstream indexes are stored, so they can be
+    /// revalidated instead of downloaded again after a restart
+    #[clap(long)]
+    pub index_cache: Option<std::path::PathBuf>,
+
+    /// How often the packages are reloaded from the upstream indexes and package folders, in
+    /// seconds. Changes to the config files and package folders are picked up immediately
+    #[clap(long, default_value_t = 300)]
+    pub upstream_refresh_interval: u64,
+    /// Where the credentials of the customers are loaded from
+    #[clap(long, value_enum, default_value = "vault")]
+    pub credentials: auth::credentials::CredentialSource,
+    /// The TOML file mapping the usernames of customers to the hashes of their passwords, used
+    /// with `--credentials file`. Changes are picked up immediately
+    #[clap(long, default_value = "credentials.toml")]
+    pub credentials_file: std::path::PathBuf,
+    /// The environment variable containing the credentials of customers as
+    /// `username:password,...`, used with `--credentials env`
+    #[clap(long, default_value = "CYPI_CUSTOMERS")]
+    pub credentials_env: String,
+
+    /// GitLab users allowed to upload using `__token__` and their access token
+    #[clap(long, env = "CYPI_UPLOAD_USERS", value_delimiter = ',')]
+    pub upload_users: Vec<String>,
+    /// GitLab groups (full paths), whose members with at least the Developer role are allowed to
+    /// upload using their access token
+    #[clap(long, env = "CYPI_UPLOAD_GROUPS", value_delimiter = ',')]
+    pub upload_groups: Vec<String>,
+    /// GitLab projects (full paths), whose members with at least the Developer role are allowed
+    /// to upload using their access token
+    #[clap(long, env = "CYPI_UPLOAD_PROJECTS", value_delimiter = ',')]
+    pub upload_projects: Vec<String>,
+    /// How long an accepted access token is remembered, before GitLab is asked again, in seconds
+    #[clap(long, default_value_t = 300)]
+    pub upload_token_ttl: u64,
+
+    /// The address of Vault, which stores the customer credentials
+    #[clap(long, env = "VAULT_ADDR", default_value = "http://127.0.0.1:8200")]
+    pub vault_url: reqwest::Url,
+    /// The Vault (Enterprise) namespace to use
+    #[clap(long, env = "VAULT_NAMESPACE")]
+    pub vault_namespace: Option<String>,
+    /// The mount of the KV secrets engine in Vault
+    #[clap(long, default_value = "secret")]
+    pub vault_mount: String,
+    /// The version of the KV secrets engine
+    #[clap(long, value_enum, default_value = "2")]
+    pub vault_kv_version: vault::KvVersion,
+    /// How to authenticate against Vault. `token` uses `VAULT_TOKEN`, `approle` logs in using
+    /// `VAULT_ROLE_ID` and `VAULT_SECRET_ID`, `kubernetes` using the service account token and
+    /// `--vault-role`
+    #[clap(long, value_enum, default_value = "token")]
+    pub vault_auth: vault::VaultAuthMethod,
+    /// The mount of the auth method, defaults to `approle` or `kubernetes`
     #[clap(long)]
-    pub sqlite_url: String,
+    pub vault_auth_mount: Option<String>,
+    /// The role to log in as, when using Kubernetes auth
+    #[clap(long, env = "VAULT_ROLE")]
+    pub vault_role: Option<String>,
+    /// The token of the Kubernetes service account, read again for every login
+    #[clap(long, default_value = "/var/run/secrets/kubernetes.io/serviceaccount/token")]
+    pub vault_kubernetes_token: std::path::PathBuf,
+    /// The path below the mount containing a secret (with a `username` and `password`) for every
+    /// customer
+    #[clap(long, default_value = "customers")]
+    pub vault_customer_path: String,
+    /// How often the customer credentials are reloaded, in seconds
+    #[clap(long, default_value_t = 60)]
+    pub vault_refresh_interval: u64,
+}
+
+impl CliArgs {
+    pub fn token_allowlist(&self) -> auth::token::TokenAllowlist {
+        auth::token::TokenAllowlist {
+            users: self.upload_users.clone(),
+            groups: self.upload_groups.clone(),
+            projects: self.upload_projects.clone(),
+        }
+    }
+
+    pub fn vault_config(&self) -> vault::VaultConfig {
+        vault::VaultConfig {
+            url: self.vault_url.clone(),
+            namespace: self.vault_namespace.clone(),
+            mount: self.vault_mount.clone(),
+            kv_version: self.vault_kv_version,
+            auth: self.vault_auth(),
+        }
+    }
+
+    fn vault_auth(&self) -> vault::VaultAuth {
+        let env = |name: &str| std::env::var(name).ok();
+
+        match self.vault_auth {
+            vault::VaultAuthMethod::Token => vault::VaultAuth::Token(env("VAULT_TOKEN")),
+            vault::VaultAuthMethod::AppRole => vault::VaultAuth::AppRole {
+                mount: self.vault_auth_mount.clone().unwrap_or_else(|| "approle".to_string()),
+                role_id: env("VAULT_ROLE_ID"),
+                secret_id: env("VAULT_SECRET_ID"),
+            },
+            vault::VaultAuthMethod::Kubernetes => vault::VaultAuth::Kubernetes {
+                mount: self.vault_auth_mount.clone().unwrap_or_else(|| "kubernetes".to_string()),
+                role: self.vault_role.clone(),
+                jwt_path: self.vault_kubernetes_token.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Validates the configs and exits, instead of running the server. Exits with a non-zero
+    /// status if any problems were found
+    CheckConfig {
+        /// Additionally request every index and read every package folder
+        #[clap(long)]
+        probe: bool,
+    },
 }
 
 /// A specific package
 #[derive(Debug, Clone)]
 pub struct Package {
-    pub src: PackageSrc,
+    /// The sources the files were loaded from, ordered by their priority
+    pub sources: Vec<PackageSrc>,
     pub files: Vec<PackageFile>,
 }
 
@@ -35,38 +200,361 @@ pub enum PackageFile {
     FilePackage {
         name: String,
         path: std::path::PathBuf,
+        hashes: FileHashes,
+        attributes: FileAttributes,
+        distribution: distribution::DistributionFilename,
+        /// The core metadata extracted from the file (only available for wheels)
+        metadata: Option<std::sync::Arc<[u8]>>,
     },
     /// A remotely stored package file (potentially requiring auth)
     RemotePackage {
         name: String,
         url: reqwest::Url,
         auth: RemotePackageAuth,
+        hashes: FileHashes,
+        attributes: FileAttributes,
+        /// The parsed filename, if the upstream file is a distribution we understand
+        distribution: Option<distribution::DistributionFilename>,
     },
 }
 
+/// The known digests of a package file, mapping the name of the hash function (e.g. `sha256`) to
+/// the hex encoded digest
+pub type FileHashes = BTreeMap<String, String>;
+
+/// The hash functions allowed in the URL fragment of a file link (PEP 503)
+pub const FRAGMENT_HASHES: &[&str] = &["md5", "sha1", "sha224", "sha256", "sha384", "sha512"];
+
+/// The additional information about a package file, exposed as `data-*` attributes on the links
+/// of the simple index (PEP 503, PEP 592, PEP 658, PEP 714)
+#[derive(Debug, Clone, Default)]
+pub struct FileAttributes {
+    /// The `Requires-Python` specifier of the file
+    pub requires_python: Option<String>,
+    /// Set if the file has been yanked, containing the (potentially empty) reason
+    pub yanked: Option<String>,
+    /// Set if the core metadata of the file is available at `{file}.metadata`, containing the
+    /// digests of the metadata file if they are known
+    pub core_metadata: Option<FileHashes>,
+    /// Whether there is a GPG signature available at `{file}.asc`
+    pub gpg_sig: Option<bool>,
+}
+
+impl PackageFile {
+    /// The filename of the package file
+    pub fn name(&self) -> &str {
+        match self {
+            Self::FilePackage { name, .. } => name,
+            Self::RemotePackage { name, .. } => name,
+        }
+    }
+
+    /// The known digests of the package file
+    pub fn hashes(&self) -> &FileHashes {
+        match self {
+            Self::FilePackage { hashes, .. } => hashes,
+            Self::RemotePackage { hashes, .. } => hashes,
+        }
+    }
+
+    /// The additional attributes of the package file
+    pub fn attributes(&self) -> &FileAttributes {
+        match self {
+            Self::FilePackage { attributes, .. } => attributes,
+            Self::RemotePackage { attributes, .. } => attributes,
+        }
+    }
+
+    /// The name, version and tags of the package file, parsed from its filename
+    pub fn distribution(&self) -> Option<&distribution::DistributionFilename> {
+        match self {
+            Self::FilePackage { distribution, .. } => Some(distribution),
+            Self::RemotePackage { distribution, .. } => distribution.as_ref(),
+        }
+    }
+
+    /// The URL fragment (`#sha256=...`) to attach to links for this file, preferring `sha256`
+    pub fn hash_fragment(&self) -> Option<String> {
+        preferred_hash(self.hashes()).map(|(name, digest)| format!("{}={}", name, digest))
+    }
+}
+
+/// Picks the digest to advertise in places where only a single one can be given, preferring
+/// `sha256`
+pub fn preferred_hash(hashes: &FileHashes) -> Option<(&String, &String)> {
+    hashes
+        .get_key_value("sha256")
+        .or_else(|| hashes.iter().next())
+}
+
 /// Auth for remotely stored packages
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum RemotePackageAuth {
     Unauthorized,
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer {
+        token: String,
+    },
+    Header {
+        name: reqwest::header::HeaderName,
+        value: reqwest::header::HeaderValue,
+    },
+}
+
+/// The most redirects followed for a single upstream request, same as the default of reqwest
+const MAX_REDIRECTS: usize = 10;
+
+/// Creates the client for requests to upstream indexes. It doesn't follow redirects, as that is
+/// done by [`RemotePackageAuth::send`]
+pub fn upstream_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("The upstream client only uses the default TLS configuration")
+}
+
+/// The blocking version of [`upstream_client`]
+pub fn upstream_blocking_client() -> reqwest::blocking::Client {
+    reqwest::blocking::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("The upstream client only uses the default TLS configuration")
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Request(reqwest::Error),
+    TooManyRedirects(reqwest::Url),
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Request(e) => write!(f, "{}", e),
+            Self::TooManyRedirects(url) => write!(f, "too many redirects, stopped at '{}'", url),
+        }
+    }
+}
+
+/// The URL the response redirects to, `None` if it is not a redirect
+fn redirect_location(
+    url: &reqwest::Url,
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+) -> Option<reqwest::Url> {
+    if !matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+
+    let location = headers.get(reqwest::header::LOCATION)?.to_str().ok()?;
+    url.join(location).ok()
+}
+
+impl RemotePackageAuth {
+    /// Sends the request built by `request` for the URL, with the credentials added.
+    ///
+    /// Redirects are followed here instead of by the client. On a redirect to another host,
+    /// reqwest only removes the standard auth headers, but custom ones (like `PRIVATE-TOKEN`) would
+    /// still be sent along. So the credentials are only added while the URL has the origin of
+    /// the original URL
+    pub async fn send(
+        &self,
+        url: reqwest::Url,
+        request: impl Fn(reqwest::Url) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, UpstreamError> {
+        let origin = url.origin();
+        let mut url = url;
+
+        for _ in 0..=MAX_REDIRECTS {
+            let mut req = request(url.clone());
+            if url.origin() == origin {
+                req = self.apply(req);
+            }
+
+            let response = req.send().await.map_err(UpstreamError::Request)?;
+            match redirect_location(&url, response.status(), response.headers()) {
+                Some(next) => url = next,
+                None => return Ok(response),
+            };
+        }
+
+        Err(UpstreamError::TooManyRedirects(url))
+    }
+
+    /// The blocking version of [`RemotePackageAuth::send`]
+    pub fn send_blocking(
+        &self,
+        url: reqwest::Url,
+        request: impl Fn(reqwest::Url) -> reqwest::blocking::RequestBuilder,
+    ) -> Result<reqwest::blocking::Response, UpstreamError> {
+        let origin = url.origin();
+        let mut url = url;
+
+        for _ in 0..=MAX_REDIRECTS {
+            let mut req = request(url.clone());
+            if url.origin() == origin {
+                req = self.apply_blocking(req);
+            }
+
+            let response = req.send().map_err(UpstreamError::Request)?;
+            match redirect_location(&url, response.status(), response.headers()) {
+                Some(next) => url = next,
+                None => return Ok(response),
+            };
+        }
+
+        Err(UpstreamError::TooManyRedirects(url))
+    }
+
+    /// Adds the credentials to the request
+    fn apply(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            Self::Unauthorized => req,
+            Self::Basic { username, password } => req.basic_auth(username, password.as_ref()),
+            Self::Bearer { token } => req.bearer_auth(token),
+            Self::Header { name, value } => req.header(name, value),
+        }
+    }
+
+    /// Adds the credentials to the request of a blocking client
+    fn apply_blocking(
+        &self,
+        req: reqwest::blocking::RequestBuilder,
+    ) -> reqwest::blocking::RequestBuilder {
+        match self {
+            Self::Unauthorized => req,
+            Self::Basic { username, password } => req.basic_auth(username, password.as_ref()),
+            Self::Bearer { token } => req.bearer_auth(token),
+            Self::Header { name, value } => req.header(name, value),
+        }
+    }
+}
+
+impl std::fmt::Debug for RemotePackageAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unauthorized => f.write_str("Unauthorized"),
+            Self::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Self::Bearer { .. } => f.debug_struct("Bearer").finish_non_exhaustive(),
+            Self::Header { name, .. } => f
+                .debug_struct("Header")
+                .field("name", name)
+                .finish_non_exhaustive(),
+        }
+    }
 }
 
 /// The basic source for a package
 #[derive(Debug, Clone)]
 pub enum PackageSrc {
-    Folder,
+    Folder { path: std::path::PathBuf },
     Index { url: reqwest::Url },
 }
 
 pub struct State {
-    pub packages: HashMap<String, Package>,
-    pub customer_packages: HashMap<String, HashSet<String>>,
+    pub packages: HashMap<PackageName, Package>,
+    pub customers: HashMap<String, Customer>,
+}
+
+/// What a single customer has access to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Customer {
+    pub packages: HashMap<PackageName, version::AllowedVersions>,
+    /// Whether the projects the packages depend on are exposed as well
+    pub include_dependencies: bool,
+    /// The packages only added because the customer's packages depend on them, these are kept up
+    /// to date by the dependency resolver
+    pub dependencies: HashSet<PackageName>,
+    pub wheel_filter: distribution::WheelFilter,
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
             packages: HashMap::new(),
-            customer_packages: HashMap::new(),
+            customers: HashMap::new(),
         }
     }
 }
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves a fixed number of requests with the response returned for the request line,
+    /// returning the received requests
+    fn serve(
+        listener: std::net::TcpListener,
+        requests: usize,
+        respond: impl Fn(&str) -> String + Send + 'static,
+    ) -> std::thread::JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let mut received = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = std::io::Read::read(&mut stream, &mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+
+                let request = String::from_utf8(request).unwrap();
+                let response = respond(request.lines().next().unwrap());
+                std::io::Write::write_all(&mut stream, response.as_bytes()).unwrap();
+                received.push(request.to_ascii_lowercase());
+            }
+            received
+        })
+    }
+
+    fn redirect(location: &str) -> String {
+        format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", location)
+    }
+
+    #[test]
+    fn credentials_are_not_sent_to_other_origins() {
+        let index = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let storage = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let index_url = format!("http://{}", index.local_addr().unwrap());
+        let storage_url = format!("http://{}", storage.local_addr().unwrap());
+
+        // The index first redirects on its own origin and then to the storage
+        let index = serve(index, 2, move |line| {
+            if line.starts_with("GET /first ") {
+                redirect("/second")
+            } else {
+                redirect(&format!("{}/file", storage_url))
+            }
+        });
+        let storage = serve(storage, 1, |_| {
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfile".to_string()
+        });
+
+        let auth = RemotePackageAuth::Header {
+            name: reqwest::header::HeaderName::from_static("private-token"),
+            value: reqwest::header::HeaderValue::from_static("secret"),
+        };
+        let client = upstream_blocking_client();
+        let url = reqwest::Url::parse(&format!("{}/first", index_url)).unwrap();
+        let response = auth.send_blocking(url, |url| client.get(url)).unwrap();
+        assert_eq!(response.text().unwrap(), "file");
+
+        let index = index.join().unwrap();
+        assert!(index.iter().all(|request| request.contains("private-token: secret")));
+        let storage = storage.join().unwrap();
+        assert!(!storage[0].contains("private-token"));
+    }
+}
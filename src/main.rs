@@ -1,7 +1,7 @@
 use clap::Parser;
 use tracing_subscriber::layer::SubscriberExt;
 
-use cypi::{State, api::AxumState, CliArgs};
+use cypi::{State, api::AxumState, auth::credentials::CredentialSource, CliArgs};
 
 fn main() {
     let args = CliArgs::parse();
@@ -9,83 +9,235 @@ fn main() {
     let registry = tracing_subscriber::Registry::default().with(tracing_subscriber::fmt::layer());
     tracing::subscriber::set_global_default(registry).unwrap();
 
+    if let Some(cypi::Command::CheckConfig { probe }) = args.command {
+        let valid = cypi::check::check_config(
+            &args.customer_config,
+            &args.package_config,
+            probe,
+            cypi::vault::VaultClient::new(reqwest::blocking::Client::new(), args.vault_config()),
+        );
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    // Only optional for the subcommands
+    let sqlite_url = match args.sqlite_url.clone() {
+        Some(url) => url,
+        None => {
+            tracing::error!("--sqlite-url is required to start the server");
+            std::process::exit(1);
+        }
+    };
+
     tracing::info!("Starting...");
 
+    let vault_config = args.vault_config();
+    if let (CredentialSource::Vault, Err(e)) = (args.credentials, vault_config.auth.validate()) {
+        tracing::error!(?e, "Missing credentials to authenticate against Vault, which are needed to load the customer credentials");
+        std::process::exit(1);
+    }
+    let vault_client = cypi::vault::VaultClient::new(reqwest::blocking::Client::new(), vault_config);
+
+    let credential_provider: Box<dyn cypi::auth::credentials::CredentialProvider> = match args.credentials {
+        CredentialSource::Vault => Box::new(cypi::auth::credentials::VaultCredentials::new(vault_client.clone(), args.vault_customer_path.clone())),
+        CredentialSource::File => Box::new(cypi::auth::credentials::FileCredentials::new(args.credentials_file.clone())),
+        CredentialSource::Env => Box::new(cypi::auth::credentials::EnvCredentials::new(args.credentials_env.clone())),
+    };
+
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
 
     let state = std::sync::Arc::new(tokio::sync::RwLock::new(State::new()));
-    let auth_state = cypi::auth::AuthState::new();
+    let auth_state = cypi::auth::AuthState::new(cypi::auth::token::GitlabTokenAuth::new(
+        args.token_allowlist(),
+        std::time::Duration::from_secs(args.upload_token_ttl),
+    ));
+
+    let (package_notifier, package_recv) = cypi::background::notifier();
+
+    let file_cache = args.file_cache_dir.as_ref().map(|dir| {
+        match cypi::cache::FileCache::open(dir, args.file_cache_max_size) {
+            Ok(cache) => cache,
+            Err(e) => {
+                tracing::error!(?e, ?dir, "Opening the file cache");
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let oauth_client = match cypi::api::gitlab_oauth_client() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!(?e, "Creating the GitLab OAuth client");
+            std::process::exit(1);
+        }
+    };
 
     let axum_state = AxumState {
         state: state.clone(),
         auth_state: auth_state.clone(),
-        client: cypi::api::gitlab_oauth_client().unwrap(),
+        client: oauth_client,
+        package_notifier: package_notifier.clone(),
+        http_client: cypi::upstream_client(),
+        file_cache,
     };
 
+    let tls_certificate = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => match cypi::tls::TlsCertificate::load(cert, key) {
+            Ok(certificate) => Some(std::sync::Arc::new(certificate)),
+            Err(e) => {
+                tracing::error!(?e, "Loading the TLS certificate");
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    // Reload the TLS certificate once it is replaced
+    let tls_notifier = tls_certificate.clone().map(|certificate| {
+        let (tls_notifier, tls_recv) = cypi::background::notifier();
+        rt.spawn_blocking(move || cypi::tls::certificate_updates(certificate, tls_recv));
+        rt.spawn(notify_periodically(
+            tls_notifier.clone(),
+            std::time::Duration::from_secs(args.tls_refresh_interval),
+            "TLS certificate",
+        ));
+        tls_notifier
+    });
+
     // Spawn the API in its own task
     let handle = rt.spawn(async move {
-        let sqlite_pool = tower_sessions_sqlx_store::sqlx::SqlitePool::connect(&args.sqlite_url).await.unwrap();
+        let sqlite_pool = tower_sessions_sqlx_store::sqlx::SqlitePool::connect(&sqlite_url).await.unwrap();
         let store = tower_sessions_sqlx_store::SqliteStore::new(sqlite_pool);
         store.migrate().await.unwrap();
 
         let router = cypi::api::api_router(axum_state, store);
 
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:3030").await.unwrap();
-        axum::serve(listener, router).await.unwrap();
+        match (args.unix_socket, tls_certificate) {
+            (Some(path), _) => {
+                // A socket left behind by a previous run would make binding fail, but anything
+                // else at the path (including the socket of a running instance) is not ours to
+                // remove
+                let is_socket = std::fs::symlink_metadata(&path)
+                    .is_ok_and(|metadata| std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type()));
+                if is_socket {
+                    match tokio::net::UnixStream::connect(&path).await {
+                        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                            exit_on_error(std::fs::remove_file(&path), "Removing the stale unix socket");
+                        }
+                        Ok(_) => {
+                            tracing::error!(?path, "Another server is already listening on the unix socket");
+                            std::process::exit(1);
+                        }
+                        Err(e) => {
+                            tracing::error!(?e, ?path, "Checking the existing unix socket");
+                            std::process::exit(1);
+                        }
+                    };
+                }
+
+                tracing::info!(?path, "Listening on unix socket");
+                let listener = exit_on_error(tokio::net::UnixListener::bind(&path), "Binding the unix socket");
+                exit_on_error(axum::serve(listener, router).await, "Serving the API");
+            }
+            (None, Some(certificate)) => {
+                let addr = std::net::SocketAddr::new(args.address, args.port);
+                tracing::info!(%addr, "Listening with TLS");
+                let listener = exit_on_error(tokio::net::TcpListener::bind(addr).await, "Binding the address");
+                let listener = exit_on_error(cypi::tls::TlsListener::new(listener, certificate), "Setting up TLS");
+                exit_on_error(axum::serve(listener, router).await, "Serving the API");
+            }
+            (None, None) => {
+                let addr = std::net::SocketAddr::new(args.address, args.port);
+                tracing::info!(%addr, "Listening");
+                let listener = exit_on_error(tokio::net::TcpListener::bind(addr).await, "Binding the address");
+                exit_on_error(axum::serve(listener, router).await, "Serving the API");
+            }
+        };
     });
 
     // Customer auth config related stuff
     let (customer_auth_notifier, customer_auth_recv) = cypi::background::notifier();
     rt.spawn_blocking({
-        move || cypi::background::customer_auth::customer_auth_updates(auth_state, customer_auth_recv, reqwest::Url::parse("http://127.0.0.1:8200").unwrap())
+        move || cypi::background::customer_auth::customer_auth_updates(auth_state, customer_auth_recv, credential_provider)
     });
-    rt.spawn(async move {
-        loop {
-            if let Err(e) = customer_auth_notifier.notify() {
-                tracing::error!(?e, "Could not notify customer auth reload");
-                return;
-            }
+    rt.spawn(notify_periodically(
+        customer_auth_notifier.clone(),
+        std::time::Duration::from_secs(args.vault_refresh_interval),
+        "customer auth",
+    ));
 
-            tokio::time::sleep(std::time::Duration::from_secs(15)).await;
-        }
+    // Exposing the dependencies of the customers' packages
+    let (dependency_notifier, dependency_recv) = cypi::background::notifier();
+    rt.spawn_blocking({
+        let state = state.clone();
+        move || cypi::background::dependencies::dependency_updates(state, dependency_recv)
     });
 
     // All the customer config related stuff
     let (customer_notifier, customer_recv) = cypi::background::notifier();
     let customer_handle = rt.spawn_blocking({
         let state = state.clone();
-        let config_path = args.customer_config;
-        move || cypi::background::customers::customer_updates(state, customer_recv, config_path)
-    });
-    rt.spawn(async move {
-        loop {
-            if let Err(e) = customer_notifier.notify() {
-                tracing::error!(?e, "Could not notify customer reload");
-                return;
-            }
-
-            tokio::time::sleep(std::time::Duration::from_secs(15)).await;
-        }
+        let config_path = args.customer_config.clone();
+        let package_config_path = args.package_config.clone();
+        move || cypi::background::customers::customer_updates(state, customer_recv, config_path, package_config_path, dependency_notifier)
     });
+    customer_notifier.notify().unwrap();
 
     // All the package config related stuff
-    let (package_notifier, package_recv) = cypi::background::notifier();
+    let folder_watcher = match cypi::background::watch::FolderWatcher::new(package_notifier.clone()) {
+        Ok(w) => Some(w),
+        Err(e) => {
+            tracing::error!(?e, "Watching package folders, changes are only picked up periodically");
+            None
+        }
+    };
     let packages_handle = rt.spawn_blocking({
         let state = state.clone();
-        let config_path = args.package_config;
-        move || cypi::background::packages::package_updates(state, package_recv, config_path)
+        let config_path = args.package_config.clone();
+        let index_cache = args.index_cache;
+        let customer_notifier = customer_notifier.clone();
+        move || cypi::background::packages::package_updates(state, package_recv, config_path, index_cache, vault_client, customer_notifier, folder_watcher)
     });
+    rt.spawn(notify_periodically(
+        package_notifier.clone(),
+        std::time::Duration::from_secs(args.upstream_refresh_interval),
+        "package",
+    ));
+
+    // Reload the configs as soon as they change, instead of waiting for the next refresh
+    let mut watched_files = vec![
+        (args.customer_config, customer_notifier.clone()),
+        (args.package_config, package_notifier.clone()),
+    ];
+    if args.credentials == CredentialSource::File {
+        watched_files.push((args.credentials_file, customer_auth_notifier.clone()));
+    }
+    if let (Some(cert), Some(key), Some(tls_notifier)) = (args.tls_cert, args.tls_key, &tls_notifier) {
+        watched_files.push((cert, tls_notifier.clone()));
+        watched_files.push((key, tls_notifier.clone()));
+    }
+    let _watcher = match cypi::background::watch::watch_files(watched_files) {
+        Ok(w) => Some(w),
+        Err(e) => {
+            tracing::error!(?e, "Watching the configs, changes are only picked up periodically");
+            None
+        }
+    };
+
+    // SIGHUP forces a reload of everything
     rt.spawn(async move {
-        loop {
-            if let Err(e) = package_notifier.notify() {
-                tracing::error!(?e, "Could not notify package reload");
-                return;
-            }
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        while hangup.recv().await.is_some() {
+            tracing::info!("Received SIGHUP, reloading everything");
 
-            tokio::time::sleep(std::time::Duration::from_secs(15)).await;
+            let notifiers = [&customer_auth_notifier, &customer_notifier, &package_notifier];
+            for notifier in notifiers.into_iter().chain(tls_notifier.as_ref()) {
+                if let Err(e) = notifier.notify() {
+                    tracing::error!(?e, "Could not notify reload");
+                }
+            }
         }
     });
 
@@ -93,3 +245,26 @@ fn main() {
     let _ = rt.block_on(customer_handle);
     let _ = rt.block_on(packages_handle);
 }
+
+/// Unwraps the result of setting up the server, logging the error and exiting if it failed
+fn exit_on_error<T>(result: std::io::Result<T>, context: &str) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            tracing::error!(?e, "{}", context);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Notifies the loader right away and then again after every interval
+async fn notify_periodically(notifier: cypi::background::Notifier, interval: std::time::Duration, name: &'static str) {
+    loop {
+        if let Err(e) = notifier.notify() {
+            tracing::error!(?e, "Could not notify {} reload", name);
+            return;
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,369 @@
+//! The core metadata of distributions (`METADATA` / `PKG-INFO`)
+
+use std::io::Read;
+
+use crate::PackageName;
+
+/// The headers of a core metadata file.
+///
+/// The format is based on email headers (RFC 822), a header can span multiple lines by indenting
+/// the continuation lines and the optional description follows after the first empty line
+#[derive(Debug, Clone, Default)]
+pub struct CoreMetadata {
+    headers: Vec<(String, String)>,
+}
+
+impl CoreMetadata {
+    pub fn parse(content: &[u8]) -> Self {
+        let content = String::from_utf8_lossy(content);
+        let mut headers: Vec<(String, String)> = Vec::new();
+
+        for line in content.lines() {
+            if line.is_empty() {
+                break;
+            }
+
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push('\n');
+                    value.push_str(line.trim());
+                }
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once(':') {
+                headers.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        Self { headers }
+    }
+
+    /// The first value of the given header (header names are case-insensitive)
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All the values of the given header, as some headers like `Requires-Dist` may be repeated
+    pub fn get_all<'s>(&'s self, key: &'s str) -> impl Iterator<Item = &'s str> + 's {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl CoreMetadata {
+    /// The names of the projects required by the distribution (`Requires-Dist`).
+    ///
+    /// Requirements that only apply to an extra are skipped, while all other environment markers
+    /// are ignored, so this includes requirements for every platform
+    pub fn required_projects(&self) -> Vec<PackageName> {
+        self.get_all("Requires-Dist")
+            .filter_map(|requirement| {
+                let (requirement, marker) = match requirement.split_once(';') {
+                    Some((requirement, marker)) => (requirement, Some(marker)),
+                    None => (requirement, None),
+                };
+
+                if marker.is_some_and(|marker| !applies_without_extras(marker)) {
+                    return None;
+                }
+
+                let requirement = requirement.trim();
+                let len = requirement
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+                    .unwrap_or(requirement.len());
+                if len == 0 {
+                    return None;
+                }
+
+                Some(PackageName::new(&requirement[..len]))
+            })
+            .collect()
+    }
+}
+
+/// Whether the requirement with the environment marker (PEP 508) is needed, when no extra is
+/// requested.
+///
+/// Only comparisons of `extra` are evaluated, all other comparisons might be true on some
+/// platform. A marker that can't be parsed is assumed to apply
+fn applies_without_extras(marker: &str) -> bool {
+    let mut parser = match MarkerParser::new(marker) {
+        Some(p) => p,
+        None => return true,
+    };
+
+    match parser.or() {
+        Some(value) if parser.tokens.len() == parser.pos => value != Some(false),
+        _ => true,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MarkerToken {
+    LeftParen,
+    RightParen,
+    /// A quoted string
+    String(String),
+    /// An environment variable (like `extra` or `python_version`) or a keyword (`and`, `or`,
+    /// `in`, `not`)
+    Word(String),
+    /// A comparison operator (like `==` or `>=`)
+    Operator(String),
+}
+
+/// Evaluates an environment marker using three-valued logic, where `None` is a value that depends
+/// on the platform
+struct MarkerParser {
+    tokens: Vec<MarkerToken>,
+    pos: usize,
+}
+
+impl MarkerParser {
+    fn new(marker: &str) -> Option<Self> {
+        let mut tokens = Vec::new();
+        let mut chars = marker.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => {}
+                '(' => tokens.push(MarkerToken::LeftParen),
+                ')' => tokens.push(MarkerToken::RightParen),
+                '\'' | '"' => {
+                    let mut value = String::new();
+                    loop {
+                        match chars.next()? {
+                            end if end == c => break,
+                            other => value.push(other),
+                        }
+                    }
+                    tokens.push(MarkerToken::String(value));
+                }
+                '<' | '>' | '=' | '!' | '~' => {
+                    let mut operator = c.to_string();
+                    while let Some(next) = chars.next_if(|n| matches!(n, '=' | '<' | '>')) {
+                        operator.push(next);
+                    }
+                    if !matches!(
+                        operator.as_str(),
+                        "<" | "<=" | ">" | ">=" | "==" | "!=" | "~=" | "==="
+                    ) {
+                        return None;
+                    }
+                    tokens.push(MarkerToken::Operator(operator));
+                }
+                c if c.is_ascii_alphanumeric() || matches!(c, '_' | '.') => {
+                    let mut word = c.to_string();
+                    while let Some(next) =
+                        chars.next_if(|n| n.is_ascii_alphanumeric() || matches!(n, '_' | '.'))
+                    {
+                        word.push(next);
+                    }
+                    tokens.push(MarkerToken::Word(word));
+                }
+                _ => return None,
+            };
+        }
+
+        Some(Self { tokens, pos: 0 })
+    }
+
+    fn peek(&self) -> Option<&MarkerToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<MarkerToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        if self.peek() == Some(&MarkerToken::Word(word.to_string())) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// `and_expr ('or' and_expr)*`
+    fn or(&mut self) -> Option<Option<bool>> {
+        let mut value = self.and()?;
+        while self.eat_word("or") {
+            value = match (value, self.and()?) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            };
+        }
+        Some(value)
+    }
+
+    /// `expr ('and' expr)*`
+    fn and(&mut self) -> Option<Option<bool>> {
+        let mut value = self.expr()?;
+        while self.eat_word("and") {
+            value = match (value, self.expr()?) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            };
+        }
+        Some(value)
+    }
+
+    /// `'(' or_expr ')'` or `value operator value`
+    fn expr(&mut self) -> Option<Option<bool>> {
+        if self.peek() == Some(&MarkerToken::LeftParen) {
+            self.pos += 1;
+            let value = self.or()?;
+            return match self.next()? {
+                MarkerToken::RightParen => Some(value),
+                _ => None,
+            };
+        }
+
+        let left = self.value()?;
+        let operator = match self.next()? {
+            MarkerToken::Operator(operator) => operator,
+            MarkerToken::Word(word) if word == "in" => word,
+            MarkerToken::Word(word) if word == "not" && self.eat_word("in") => "not in".to_string(),
+            _ => return None,
+        };
+        let right = self.value()?;
+
+        Some(compare_extra(&left, &operator, &right))
+    }
+
+    fn value(&mut self) -> Option<MarkerToken> {
+        match self.next()? {
+            MarkerToken::Word(word) if matches!(word.as_str(), "and" | "or" | "in" | "not") => None,
+            token @ (MarkerToken::Word(_) | MarkerToken::String(_)) => Some(token),
+            _ => None,
+        }
+    }
+}
+
+/// Evaluates a comparison with `extra` being empty, as no extra is requested. Other comparisons
+/// depend on the platform
+fn compare_extra(left: &MarkerToken, operator: &str, right: &MarkerToken) -> Option<bool> {
+    let is_extra = |token: &MarkerToken| *token == MarkerToken::Word("extra".to_string());
+
+    match (left, right) {
+        (extra, MarkerToken::String(value)) if is_extra(extra) => match operator {
+            "==" | "===" => Some(value.is_empty()),
+            "!=" => Some(!value.is_empty()),
+            // An empty string is part of every string
+            "in" => Some(true),
+            "not in" => Some(false),
+            _ => None,
+        },
+        (MarkerToken::String(value), extra) if is_extra(extra) => match operator {
+            "==" | "===" => Some(value.is_empty()),
+            "!=" => Some(!value.is_empty()),
+            "in" => Some(value.is_empty()),
+            "not in" => Some(!value.is_empty()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum ExtractMetadataError {
+    Io(std::io::Error),
+    Zip(zip::result::ZipError),
+    MissingMetadata,
+}
+
+/// Extracts the raw `{name}-{version}.dist-info/METADATA` file from a wheel
+pub fn extract_wheel_metadata(path: &std::path::Path) -> Result<Vec<u8>, ExtractMetadataError> {
+    let file = std::fs::File::open(path).map_err(ExtractMetadataError::Io)?;
+    let mut archive = zip::ZipArchive::new(file).map_err(ExtractMetadataError::Zip)?;
+
+    let metadata_name = archive
+        .file_names()
+        .find(|name| {
+            name.split_once('/')
+                .map(|(dir, file)| dir.ends_with(".dist-info") && file == "METADATA")
+                .unwrap_or(false)
+        })
+        .map(|name| name.to_string())
+        .ok_or(ExtractMetadataError::MissingMetadata)?;
+
+    let mut entry = archive
+        .by_name(&metadata_name)
+        .map_err(ExtractMetadataError::Zip)?;
+
+    let mut content = Vec::new();
+    entry
+        .read_to_end(&mut content)
+        .map_err(ExtractMetadataError::Io)?;
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn required(requirements: &[&str]) -> Vec<String> {
+        let content: String = requirements
+            .iter()
+            .map(|r| format!("Requires-Dist: {}\n", r))
+            .collect();
+        CoreMetadata::parse(format!("Metadata-Version: 2.1\nName: foo\n{}", content).as_bytes())
+            .required_projects()
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn requirements_without_markers() {
+        assert_eq!(
+            required(&["numpy>=1.20", "Typing_Extensions (>=4.0)", "requests[socks]"]),
+            ["numpy", "typing-extensions", "requests"]
+        );
+    }
+
+    #[test]
+    fn requirements_for_extras_are_skipped() {
+        assert_eq!(
+            required(&[
+                "pytest ; extra == 'test'",
+                "sphinx; extra == \"docs\" and python_version >= \"3.8\"",
+                "mypy ; (extra == 'dev' or extra == 'typing')",
+                "coverage ; 'test' == extra",
+            ]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn platform_markers_are_kept() {
+        assert_eq!(
+            required(&[
+                "pywin32 ; sys_platform == 'win32'",
+                "tomli ; python_version < '3.11'",
+                "colorama ; platform_system == 'Windows' or extra == 'color'",
+                "importlib-metadata ; python_version < '3.8' and extra != 'minimal'",
+                // Mentions `extra` in a value, not as the variable
+                "extras-helper ; platform_release == 'extra'",
+            ]),
+            ["pywin32", "tomli", "colorama", "importlib-metadata", "extras-helper"]
+        );
+    }
+
+    #[test]
+    fn unparseable_markers_are_kept() {
+        assert_eq!(required(&["foo ; extra == 'test"]), ["foo"]);
+        assert_eq!(required(&["bar ; (extra == 'test'"]), ["bar"]);
+    }
+}
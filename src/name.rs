@@ -0,0 +1,90 @@
+//! Normalized package names (PEP 503)
+
+/// The name of a package, normalized as described in PEP 503.
+///
+/// All runs of `-`, `_` and `.` are replaced by a single `-` and the name is lowercased, so
+/// `Scikit_Learn` and `scikit-learn` refer to the same package.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PackageName(String);
+
+impl PackageName {
+    pub fn new(raw: &str) -> Self {
+        let mut normalized = String::with_capacity(raw.len());
+        let mut in_separator = false;
+
+        for c in raw.chars() {
+            if matches!(c, '-' | '_' | '.') {
+                if !in_separator {
+                    normalized.push('-');
+                }
+                in_separator = true;
+            } else {
+                normalized.push(c.to_ascii_lowercase());
+                in_separator = false;
+            }
+        }
+
+        Self(normalized)
+    }
+
+    /// Checks if the raw name is already in its normalized form
+    pub fn is_normalized(raw: &str) -> bool {
+        Self::new(raw).0 == raw
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for PackageName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for PackageName {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PackageName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        Ok(Self::new(&raw))
+    }
+}
+
+impl serde::Serialize for PackageName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_separators_and_case() {
+        assert_eq!(PackageName::new("Scikit_Learn").as_str(), "scikit-learn");
+        assert_eq!(PackageName::new("zope.interface").as_str(), "zope-interface");
+        assert_eq!(PackageName::new("Foo-._Bar__baz").as_str(), "foo-bar-baz");
+        assert_eq!(PackageName::new("numpy").as_str(), "numpy");
+    }
+
+    #[test]
+    fn detects_normalized_names() {
+        assert!(PackageName::is_normalized("scikit-learn"));
+        assert!(!PackageName::is_normalized("scikit_learn"));
+        assert!(!PackageName::is_normalized("Numpy"));
+        assert!(!PackageName::is_normalized("foo--bar"));
+    }
+}
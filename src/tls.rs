@@ -0,0 +1,173 @@
+//! Terminating TLS in the server itself, with certificates that can be replaced while running
+
+use std::sync::Arc;
+
+use crate::background::NotificationReceiver;
+
+/// How long a client has to complete the TLS handshake, so slow clients can't pile up
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The certificate and key used to terminate TLS, reloaded from their files on demand
+pub struct TlsCertificate {
+    cert_path: std::path::PathBuf,
+    key_path: std::path::PathBuf,
+    acceptor: std::sync::RwLock<tokio_rustls::TlsAcceptor>,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum LoadTlsError {
+    Reading {
+        path: std::path::PathBuf,
+        error: std::io::Error,
+    },
+    MissingCertificate(std::path::PathBuf),
+    MissingKey(std::path::PathBuf),
+    InvalidCertificate(tokio_rustls::rustls::Error),
+}
+
+impl TlsCertificate {
+    /// Loads the PEM encoded certificate chain and private key
+    pub fn load(
+        cert_path: impl Into<std::path::PathBuf>,
+        key_path: impl Into<std::path::PathBuf>,
+    ) -> Result<Self, LoadTlsError> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let acceptor = load_acceptor(&cert_path, &key_path)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            acceptor: std::sync::RwLock::new(acceptor),
+        })
+    }
+
+    /// Loads the certificate and key again, only connections accepted afterwards use the new
+    /// certificate. The current certificate is kept if loading fails
+    pub fn reload(&self) -> Result<(), LoadTlsError> {
+        let acceptor = load_acceptor(&self.cert_path, &self.key_path)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
+
+fn load_acceptor(
+    cert_path: &std::path::Path,
+    key_path: &std::path::Path,
+) -> Result<tokio_rustls::TlsAcceptor, LoadTlsError> {
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).map_err(|error| LoadTlsError::Reading {
+            path: path.to_path_buf(),
+            error,
+        })
+    };
+
+    let cert_content = read(cert_path)?;
+    let certs = rustls_pemfile::certs(&mut cert_content.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| LoadTlsError::Reading {
+            path: cert_path.to_path_buf(),
+            error,
+        })?;
+    if certs.is_empty() {
+        return Err(LoadTlsError::MissingCertificate(cert_path.to_path_buf()));
+    }
+
+    let key_content = read(key_path)?;
+    let key = rustls_pemfile::private_key(&mut key_content.as_slice())
+        .map_err(|error| LoadTlsError::Reading {
+            path: key_path.to_path_buf(),
+            error,
+        })?
+        .ok_or_else(|| LoadTlsError::MissingKey(key_path.to_path_buf()))?;
+
+    let mut config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(LoadTlsError::InvalidCertificate)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reloads the certificate every time it is notified
+#[tracing::instrument(skip(certificate, recv))]
+pub fn certificate_updates(certificate: Arc<TlsCertificate>, mut recv: NotificationReceiver) {
+    loop {
+        if let Err(e) = recv.listen() {
+            tracing::error!(?e, "NotificationReceiver is broken");
+            return;
+        }
+
+        match certificate.reload() {
+            Ok(()) => tracing::info!("Reloaded TLS certificate"),
+            Err(e) => tracing::error!(?e, "Reloading TLS certificate, keeping the current one"),
+        };
+    }
+}
+
+/// Accepts TCP connections and performs the TLS handshake, before handing them to the server
+pub struct TlsListener {
+    local_addr: std::net::SocketAddr,
+    incoming: tokio::sync::mpsc::Receiver<(
+        tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+        std::net::SocketAddr,
+    )>,
+}
+
+impl TlsListener {
+    /// Needs to be called from within the tokio runtime, as it spawns the task accepting the
+    /// connections
+    pub fn new(mut listener: tokio::net::TcpListener, certificate: Arc<TlsCertificate>) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = tokio::sync::mpsc::channel(32);
+
+        tokio::spawn(async move {
+            loop {
+                // Retries on errors by itself
+                let (stream, addr) = axum::serve::Listener::accept(&mut listener).await;
+
+                // Handshakes happen in their own tasks, so a single slow client does not block
+                // accepting other connections
+                let acceptor = certificate.acceptor();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!(?e, ?addr, "TLS handshake failed"),
+                        Err(_) => tracing::debug!(?addr, "TLS handshake timed out"),
+                    };
+                });
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            incoming: rx,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
+    type Addr = std::net::SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(connection) => connection,
+            // The accepting task never stops on its own
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
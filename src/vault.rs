@@ -0,0 +1,408 @@
+//! Reading secrets from the KV store of Vault
+
+use std::collections::HashMap;
+
+/// How to reach Vault and where the secrets are stored
+#[derive(Debug, Clone)]
+pub struct VaultConfig {
+    pub url: reqwest::Url,
+    /// The namespace (Vault Enterprise) all requests are made in
+    pub namespace: Option<String>,
+    /// The mount of the KV secrets engine
+    pub mount: String,
+    pub kv_version: KvVersion,
+    pub auth: VaultAuth,
+}
+
+/// The version of the KV secrets engine, which changes the paths of the API
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum KvVersion {
+    #[value(name = "1")]
+    V1,
+    #[value(name = "2")]
+    V2,
+}
+
+/// The supported ways of authenticating against Vault
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum VaultAuthMethod {
+    Token,
+    #[value(name = "approle")]
+    AppRole,
+    Kubernetes,
+}
+
+/// How to obtain a token. Missing credentials are only reported once a token is needed, use
+/// [`VaultAuth::validate`] to check them upfront
+#[derive(Clone)]
+pub enum VaultAuth {
+    /// A static token, which can only be renewed but not obtained again once it expired
+    Token(Option<String>),
+    AppRole {
+        mount: String,
+        role_id: Option<String>,
+        secret_id: Option<String>,
+    },
+    /// Logs in using the token of the Kubernetes service account, which is read again for
+    /// every login as it is rotated by Kubernetes
+    Kubernetes {
+        mount: String,
+        role: Option<String>,
+        jwt_path: std::path::PathBuf,
+    },
+}
+
+impl std::fmt::Debug for VaultAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Token(_) => f.debug_tuple("Token").finish_non_exhaustive(),
+            Self::AppRole { mount, role_id, .. } => f
+                .debug_struct("AppRole")
+                .field("mount", mount)
+                .field("role_id", role_id)
+                .finish_non_exhaustive(),
+            Self::Kubernetes {
+                mount,
+                role,
+                jwt_path,
+            } => f
+                .debug_struct("Kubernetes")
+                .field("mount", mount)
+                .field("role", role)
+                .field("jwt_path", jwt_path)
+                .finish(),
+        }
+    }
+}
+
+impl VaultAuth {
+    /// Checks that all the credentials needed to obtain a token are present
+    pub fn validate(&self) -> Result<(), VaultError> {
+        let missing = match self {
+            Self::Token(None) => Some("VAULT_TOKEN"),
+            Self::AppRole { role_id: None, .. } => Some("VAULT_ROLE_ID"),
+            Self::AppRole { secret_id: None, .. } => Some("VAULT_SECRET_ID"),
+            Self::Kubernetes { role: None, .. } => Some("VAULT_ROLE"),
+            _ => None,
+        };
+
+        match missing {
+            Some(name) => Err(VaultError::MissingCredentials(name)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The token shared between all the clones of a client
+struct VaultToken {
+    token: String,
+    renewable: bool,
+    /// How long the token is valid after it was obtained, `None` if it does not expire
+    ttl: Option<std::time::Duration>,
+    obtained: std::time::Instant,
+}
+
+impl VaultToken {
+    fn expired(&self) -> bool {
+        self.ttl.is_some_and(|ttl| self.obtained.elapsed() >= ttl)
+    }
+
+    /// Tokens are renewed once two thirds of their TTL passed, leaving enough time to retry or
+    /// log in again before they expire
+    fn should_renew(&self) -> bool {
+        self.renewable && self.ttl.is_some_and(|ttl| self.obtained.elapsed() >= ttl * 2 / 3)
+    }
+}
+
+#[derive(Clone)]
+pub struct VaultClient {
+    http_client: reqwest::blocking::Client,
+    config: VaultConfig,
+    token: std::sync::Arc<std::sync::Mutex<Option<VaultToken>>>,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum VaultError {
+    /// The credentials (named after their environment variable) to obtain a token are missing
+    MissingCredentials(&'static str),
+    ReadingJwt(std::io::Error),
+    JoiningUrls,
+    SendingRequest(reqwest::Error),
+    ParseResponse(reqwest::Error),
+    MissingKey { path: String, key: String },
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Response<T> {
+    data: T,
+}
+
+/// The additional wrapping of the secret data in version 2 of the KV engine
+#[derive(Debug, serde::Deserialize)]
+struct DataResponse<T> {
+    data: T,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ListResponse {
+    keys: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AuthResponse {
+    auth: AuthData,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AuthData {
+    client_token: String,
+    lease_duration: u64,
+    renewable: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LookupData {
+    ttl: u64,
+    renewable: bool,
+}
+
+impl VaultClient {
+    pub fn new(http_client: reqwest::blocking::Client, config: VaultConfig) -> Self {
+        Self {
+            http_client,
+            config,
+            token: Default::default(),
+        }
+    }
+
+    /// Reads the secret at the path (relative to the mount)
+    pub fn read_secret<T>(&self, path: &str) -> Result<T, VaultError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let api_path = match self.config.kv_version {
+            KvVersion::V1 => path.to_string(),
+            KvVersion::V2 => format!("data/{path}"),
+        };
+        let response = self.request(reqwest::Method::GET, &api_path)?;
+
+        match self.config.kv_version {
+            KvVersion::V1 => response
+                .json::<Response<T>>()
+                .map(|content| content.data)
+                .map_err(VaultError::ParseResponse),
+            KvVersion::V2 => response
+                .json::<Response<DataResponse<T>>>()
+                .map(|content| content.data.data)
+                .map_err(VaultError::ParseResponse),
+        }
+    }
+
+    /// Reads a single key of the secret at the path
+    pub fn read_key(&self, path: &str, key: &str) -> Result<String, VaultError> {
+        let secret: HashMap<String, serde_json::Value> = self.read_secret(path)?;
+
+        secret
+            .get(key)
+            .and_then(|value| value.as_str())
+            .map(|value| value.to_string())
+            .ok_or_else(|| VaultError::MissingKey {
+                path: path.to_string(),
+                key: key.to_string(),
+            })
+    }
+
+    /// Lists the names of the secrets (and folders, ending in `/`) below the path
+    pub fn list(&self, path: &str) -> Result<Vec<String>, VaultError> {
+        let api_path = match self.config.kv_version {
+            KvVersion::V1 => path.to_string(),
+            KvVersion::V2 => format!("metadata/{path}"),
+        };
+        let list_method = reqwest::Method::from_bytes(b"LIST").expect("LIST is a valid method");
+
+        self.request(list_method, &api_path)?
+            .json::<Response<ListResponse>>()
+            .map(|content| content.data.keys)
+            .map_err(VaultError::ParseResponse)
+    }
+
+    /// Sends a request to the secrets engine. If Vault rejects the token, a new one is obtained
+    /// and the request is retried once
+    fn request(
+        &self,
+        method: reqwest::Method,
+        api_path: &str,
+    ) -> Result<reqwest::blocking::Response, VaultError> {
+        let target_url = self
+            .config
+            .url
+            .join(&format!("/v1/{}/{}", self.config.mount, api_path))
+            .map_err(|_e| VaultError::JoiningUrls)?;
+        tracing::debug!(?target_url, "Requesting Vault");
+
+        let send = |token: &str| {
+            self.request_builder(method.clone(), target_url.clone())
+                .bearer_auth(token)
+                .send()
+                .map_err(VaultError::SendingRequest)
+        };
+
+        let token = self.token()?;
+        let mut response = send(&token)?;
+        if response.status() == reqwest::StatusCode::FORBIDDEN {
+            tracing::warn!("Vault rejected the token, authenticating again");
+            self.forget_token(&token);
+            response = send(&self.token()?)?;
+        }
+
+        response.error_for_status().map_err(VaultError::SendingRequest)
+    }
+
+    fn request_builder(
+        &self,
+        method: reqwest::Method,
+        url: reqwest::Url,
+    ) -> reqwest::blocking::RequestBuilder {
+        let req_builder = self.http_client.request(method, url);
+        match &self.config.namespace {
+            Some(namespace) => req_builder.header("X-Vault-Namespace", namespace),
+            None => req_builder,
+        }
+    }
+
+    /// The current token, logging in or renewing it first if needed
+    fn token(&self) -> Result<String, VaultError> {
+        let mut current = self.token.lock().unwrap();
+
+        match current.as_ref() {
+            Some(token) if token.expired() => {
+                tracing::info!("Vault token expired, authenticating again");
+                *current = Some(self.login()?);
+            }
+            Some(token) if token.should_renew() => match self.renew(&token.token) {
+                Ok(renewed) => *current = Some(renewed),
+                Err(e) => {
+                    tracing::warn!(?e, "Renewing Vault token, authenticating again");
+                    *current = Some(self.login()?);
+                }
+            },
+            Some(_) => {}
+            None => *current = Some(self.login()?),
+        };
+
+        Ok(current
+            .as_ref()
+            .map(|token| token.token.clone())
+            .expect("The token was just set"))
+    }
+
+    /// Drops the token, unless another request already replaced it
+    fn forget_token(&self, token: &str) {
+        let mut current = self.token.lock().unwrap();
+        if current.as_ref().is_some_and(|current| current.token == token) {
+            *current = None;
+        }
+    }
+
+    /// Obtains a new token using the configured auth method. A static token is only looked up,
+    /// to know when it needs to be renewed
+    fn login(&self) -> Result<VaultToken, VaultError> {
+        self.config.auth.validate()?;
+
+        let (mount, body) = match &self.config.auth {
+            VaultAuth::Token(token) => {
+                let token = token.as_deref().ok_or(VaultError::MissingCredentials("VAULT_TOKEN"))?;
+                return self.lookup(token);
+            }
+            VaultAuth::AppRole {
+                mount,
+                role_id,
+                secret_id,
+            } => (
+                mount,
+                serde_json::json!({ "role_id": role_id, "secret_id": secret_id }),
+            ),
+            VaultAuth::Kubernetes {
+                mount,
+                role,
+                jwt_path,
+            } => {
+                let jwt = std::fs::read_to_string(jwt_path).map_err(VaultError::ReadingJwt)?;
+                (
+                    mount,
+                    serde_json::json!({ "role": role, "jwt": jwt.trim() }),
+                )
+            }
+        };
+
+        let target_url = self
+            .config
+            .url
+            .join(&format!("/v1/auth/{mount}/login"))
+            .map_err(|_e| VaultError::JoiningUrls)?;
+
+        let token = self.send_auth(self.request_builder(reqwest::Method::POST, target_url).json(&body))?;
+        tracing::info!(ttl = ?token.ttl, renewable = token.renewable, "Authenticated against Vault");
+
+        Ok(token)
+    }
+
+    fn lookup(&self, token: &str) -> Result<VaultToken, VaultError> {
+        let target_url = self
+            .config
+            .url
+            .join("/v1/auth/token/lookup-self")
+            .map_err(|_e| VaultError::JoiningUrls)?;
+
+        let content = self
+            .request_builder(reqwest::Method::GET, target_url)
+            .bearer_auth(token)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map_err(VaultError::SendingRequest)?
+            .json::<Response<LookupData>>()
+            .map_err(VaultError::ParseResponse)?;
+
+        Ok(VaultToken {
+            token: token.to_string(),
+            renewable: content.data.renewable,
+            ttl: ttl(content.data.ttl),
+            obtained: std::time::Instant::now(),
+        })
+    }
+
+    fn renew(&self, token: &str) -> Result<VaultToken, VaultError> {
+        let target_url = self
+            .config
+            .url
+            .join("/v1/auth/token/renew-self")
+            .map_err(|_e| VaultError::JoiningUrls)?;
+
+        let token = self.send_auth(self.request_builder(reqwest::Method::POST, target_url).bearer_auth(token))?;
+        tracing::debug!(ttl = ?token.ttl, "Renewed Vault token");
+
+        Ok(token)
+    }
+
+    fn send_auth(&self, req_builder: reqwest::blocking::RequestBuilder) -> Result<VaultToken, VaultError> {
+        let content = req_builder
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map_err(VaultError::SendingRequest)?
+            .json::<AuthResponse>()
+            .map_err(VaultError::ParseResponse)?;
+
+        Ok(VaultToken {
+            token: content.auth.client_token,
+            renewable: content.auth.renewable,
+            ttl: ttl(content.auth.lease_duration),
+            obtained: std::time::Instant::now(),
+        })
+    }
+}
+
+/// Vault uses a TTL of 0 for tokens which don't expire
+fn ttl(seconds: u64) -> Option<std::time::Duration> {
+    (seconds > 0).then(|| std::time::Duration::from_secs(seconds))
+}
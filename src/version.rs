@@ -0,0 +1,809 @@
+//! Package versions as described in PEP 440
+
+/// A parsed (and normalized) PEP 440 version
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub epoch: u64,
+    pub release: Vec<u64>,
+    pub pre: Option<(PreRelease, u64)>,
+    pub post: Option<u64>,
+    pub dev: Option<u64>,
+    pub local: Vec<LocalSegment>,
+}
+
+/// The kind of a pre-release, ordered from earliest to latest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PreRelease {
+    Alpha,
+    Beta,
+    ReleaseCandidate,
+}
+
+/// A segment of the local version label, numeric segments sort after alphanumeric ones
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LocalSegment {
+    String(String),
+    Number(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionParseError {
+    Empty,
+    InvalidEpoch,
+    MissingRelease,
+    TrailingCharacters(String),
+}
+
+impl Version {
+    /// Parses a version, accepting all the alternative spellings PEP 440 allows
+    pub fn parse(raw: &str) -> Result<Self, VersionParseError> {
+        let lowered = raw.trim().to_ascii_lowercase();
+        if lowered.is_empty() {
+            return Err(VersionParseError::Empty);
+        }
+
+        let mut parser = Parser {
+            input: lowered.strip_prefix('v').unwrap_or(&lowered),
+            pos: 0,
+        };
+
+        let mut epoch = 0;
+        if let Some(idx) = parser.input.find('!') {
+            epoch = parser.input[..idx]
+                .parse()
+                .map_err(|_e| VersionParseError::InvalidEpoch)?;
+            parser.pos = idx + 1;
+        }
+
+        let mut release = Vec::new();
+        while let Some(n) = parser.number() {
+            release.push(n);
+
+            let checkpoint = parser.pos;
+            if !parser.eat(".") || !parser.peek_digit() {
+                parser.pos = checkpoint;
+                break;
+            }
+        }
+        if release.is_empty() {
+            return Err(VersionParseError::MissingRelease);
+        }
+
+        let pre = parser.pre_release();
+        let post = parser.post_release();
+        let dev = parser.dev_release();
+        let local = parser.local();
+
+        if parser.pos != parser.input.len() {
+            return Err(VersionParseError::TrailingCharacters(
+                parser.input[parser.pos..].to_string(),
+            ));
+        }
+
+        Ok(Self {
+            epoch,
+            release,
+            pre,
+            post,
+            dev,
+            local,
+        })
+    }
+
+    /// Whether this is a pre-release (or development release)
+    pub fn is_prerelease(&self) -> bool {
+        self.pre.is_some() || self.dev.is_some()
+    }
+
+    /// The version without its local label
+    pub fn without_local(&self) -> Self {
+        Self {
+            local: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// The release segment without any trailing zeros, as those don't affect comparisons
+    fn trimmed_release(&self) -> &[u64] {
+        let len = self
+            .release
+            .iter()
+            .rposition(|n| *n != 0)
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        &self.release[..len]
+    }
+
+    fn sort_key(&self) -> SortKey<'_> {
+        let pre = match (self.pre, self.post, self.dev) {
+            (Some((kind, n)), _, _) => Bound::Value((kind, n)),
+            // `1.0.dev1` sorts before `1.0a1`
+            (None, None, Some(_)) => Bound::NegativeInfinity,
+            _ => Bound::PositiveInfinity,
+        };
+        let post = match self.post {
+            Some(n) => Bound::Value(n),
+            None => Bound::NegativeInfinity,
+        };
+        let dev = match self.dev {
+            Some(n) => Bound::Value(n),
+            None => Bound::PositiveInfinity,
+        };
+
+        (self.epoch, self.trimmed_release(), pre, post, dev, &self.local)
+    }
+}
+
+type SortKey<'v> = (
+    u64,
+    &'v [u64],
+    Bound<(PreRelease, u64)>,
+    Bound<u64>,
+    Bound<u64>,
+    &'v [LocalSegment],
+);
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Bound<T> {
+    NegativeInfinity,
+    Value(T),
+    PositiveInfinity,
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = VersionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.epoch != 0 {
+            write!(f, "{}!", self.epoch)?;
+        }
+
+        let release: Vec<String> = self.release.iter().map(|n| n.to_string()).collect();
+        write!(f, "{}", release.join("."))?;
+
+        if let Some((kind, n)) = self.pre {
+            let kind = match kind {
+                PreRelease::Alpha => "a",
+                PreRelease::Beta => "b",
+                PreRelease::ReleaseCandidate => "rc",
+            };
+            write!(f, "{}{}", kind, n)?;
+        }
+        if let Some(n) = self.post {
+            write!(f, ".post{}", n)?;
+        }
+        if let Some(n) = self.dev {
+            write!(f, ".dev{}", n)?;
+        }
+
+        if !self.local.is_empty() {
+            let local: Vec<String> = self
+                .local
+                .iter()
+                .map(|s| match s {
+                    LocalSegment::String(s) => s.clone(),
+                    LocalSegment::Number(n) => n.to_string(),
+                })
+                .collect();
+            write!(f, "+{}", local.join("."))?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Parser<'i> {
+    input: &'i str,
+    pos: usize,
+}
+
+impl<'i> Parser<'i> {
+    fn rest(&self) -> &'i str {
+        &self.input[self.pos..]
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            self.pos += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_separator(&mut self) -> bool {
+        self.eat(".") || self.eat("-") || self.eat("_")
+    }
+
+    fn peek_digit(&self) -> bool {
+        self.rest().starts_with(|c: char| c.is_ascii_digit())
+    }
+
+    fn number(&mut self) -> Option<u64> {
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return None;
+        }
+
+        let n = self.rest()[..len].parse().ok()?;
+        self.pos += len;
+        Some(n)
+    }
+
+    /// Parses the optional `(sep)?(number)?` after a pre/post/dev marker, implicitly `0`
+    fn implicit_number(&mut self) -> u64 {
+        let checkpoint = self.pos;
+        self.eat_separator();
+        match self.number() {
+            Some(n) => n,
+            None => {
+                self.pos = checkpoint;
+                0
+            }
+        }
+    }
+
+    fn pre_release(&mut self) -> Option<(PreRelease, u64)> {
+        const SPELLINGS: &[(&str, PreRelease)] = &[
+            ("alpha", PreRelease::Alpha),
+            ("beta", PreRelease::Beta),
+            ("preview", PreRelease::ReleaseCandidate),
+            ("pre", PreRelease::ReleaseCandidate),
+            ("rc", PreRelease::ReleaseCandidate),
+            ("a", PreRelease::Alpha),
+            ("b", PreRelease::Beta),
+            ("c", PreRelease::ReleaseCandidate),
+        ];
+
+        let checkpoint = self.pos;
+        self.eat_separator();
+
+        for (spelling, kind) in SPELLINGS {
+            if self.eat(spelling) {
+                return Some((*kind, self.implicit_number()));
+            }
+        }
+
+        self.pos = checkpoint;
+        None
+    }
+
+    fn post_release(&mut self) -> Option<u64> {
+        let checkpoint = self.pos;
+
+        // The implicit post release `1.0-1`
+        if self.eat("-") {
+            if let Some(n) = self.number() {
+                return Some(n);
+            }
+            self.pos = checkpoint;
+        }
+
+        self.eat_separator();
+        for spelling in ["post", "rev", "r"] {
+            if self.eat(spelling) {
+                return Some(self.implicit_number());
+            }
+        }
+
+        self.pos = checkpoint;
+        None
+    }
+
+    fn dev_release(&mut self) -> Option<u64> {
+        let checkpoint = self.pos;
+        self.eat_separator();
+
+        if self.eat("dev") {
+            return Some(self.implicit_number());
+        }
+
+        self.pos = checkpoint;
+        None
+    }
+
+    fn local(&mut self) -> Vec<LocalSegment> {
+        if !self.eat("+") {
+            return Vec::new();
+        }
+
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')))
+            .unwrap_or(self.rest().len());
+        let label = &self.rest()[..len];
+        self.pos += len;
+
+        label
+            .split(['.', '-', '_'])
+            .map(|segment| match segment.parse() {
+                Ok(n) => LocalSegment::Number(n),
+                Err(_) => LocalSegment::String(segment.to_string()),
+            })
+            .collect()
+    }
+}
+
+/// A comma separated list of version specifiers (PEP 440), a version has to match all of them.
+///
+/// An empty list (written as `""` or `"*"`) matches every version
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VersionSpecifiers(pub Vec<VersionSpecifier>);
+
+/// A single version specifier, like `>=1.0` or `==2.1.*`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionSpecifier {
+    pub operator: Operator,
+    pub version: Version,
+    /// Set for the prefix matching of `==1.2.*` and `!=1.2.*`
+    pub wildcard: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    /// `~=`
+    Compatible,
+    /// `==`
+    Equal,
+    /// `!=`
+    NotEqual,
+    /// `<=`
+    LessEqual,
+    /// `>=`
+    GreaterEqual,
+    /// `<`
+    Less,
+    /// `>`
+    Greater,
+    /// `===`
+    ArbitraryEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpecifierParseError {
+    MissingOperator(String),
+    InvalidVersion(String, VersionParseError),
+    /// Only `==` and `!=` support the `.*` suffix
+    UnexpectedWildcard(String),
+    /// `~=` needs at least two release segments, like `~=1.4`
+    InvalidCompatible(String),
+}
+
+impl std::fmt::Display for SpecifierParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingOperator(raw) => write!(f, "missing operator in '{}'", raw),
+            Self::InvalidVersion(raw, e) => write!(f, "invalid version in '{}': {:?}", raw, e),
+            Self::UnexpectedWildcard(raw) => {
+                write!(f, "'.*' is only allowed with '==' and '!=' in '{}'", raw)
+            }
+            Self::InvalidCompatible(raw) => {
+                write!(f, "'~=' needs at least two release segments in '{}'", raw)
+            }
+        }
+    }
+}
+
+impl VersionSpecifiers {
+    pub fn parse(raw: &str) -> Result<Self, SpecifierParseError> {
+        let raw = raw.trim();
+        if raw.is_empty() || raw == "*" {
+            return Ok(Self::default());
+        }
+
+        raw.split(',')
+            .map(VersionSpecifier::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+
+    /// Whether the version matches all the specifiers.
+    ///
+    /// Unlike installers, pre-releases are not excluded by default, as choosing whether to install
+    /// them is up to the client
+    pub fn contains(&self, version: &Version) -> bool {
+        self.0.iter().all(|specifier| specifier.contains(version))
+    }
+
+    /// Whether the specifiers match every version
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The versions matching any of multiple sets of specifiers, as a customer can get the same
+/// package from multiple bundles with different versions
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowedVersions(Vec<VersionSpecifiers>);
+
+impl AllowedVersions {
+    /// Allows every version
+    pub fn all() -> Self {
+        Self(vec![VersionSpecifiers::default()])
+    }
+
+    /// Also allows the versions matching the specifiers
+    pub fn union(&mut self, specifiers: &VersionSpecifiers) {
+        if self.is_all() {
+            return;
+        }
+
+        if specifiers.is_empty() {
+            *self = Self::all();
+        } else if !self.0.contains(specifiers) {
+            self.0.push(specifiers.clone());
+        }
+    }
+
+    /// Whether the version matches any of the specifiers
+    pub fn contains(&self, version: &Version) -> bool {
+        self.0.iter().any(|specifiers| specifiers.contains(version))
+    }
+
+    /// Whether every version is allowed
+    pub fn is_all(&self) -> bool {
+        self.0.iter().any(|specifiers| specifiers.is_empty())
+    }
+}
+
+impl From<VersionSpecifiers> for AllowedVersions {
+    fn from(specifiers: VersionSpecifiers) -> Self {
+        Self(vec![specifiers])
+    }
+}
+
+impl VersionSpecifier {
+    pub fn parse(raw: &str) -> Result<Self, SpecifierParseError> {
+        const OPERATORS: &[(&str, Operator)] = &[
+            ("===", Operator::ArbitraryEqual),
+            ("~=", Operator::Compatible),
+            ("==", Operator::Equal),
+            ("!=", Operator::NotEqual),
+            ("<=", Operator::LessEqual),
+            (">=", Operator::GreaterEqual),
+            ("<", Operator::Less),
+            (">", Operator::Greater),
+        ];
+
+        let trimmed = raw.trim();
+        let (operator, rest) = OPERATORS
+            .iter()
+            .find_map(|(prefix, operator)| trimmed.strip_prefix(prefix).map(|rest| (*operator, rest)))
+            .ok_or_else(|| SpecifierParseError::MissingOperator(trimmed.to_string()))?;
+
+        let rest = rest.trim();
+        let (version, wildcard) = match rest.strip_suffix(".*") {
+            Some(version) => (version, true),
+            None => (rest, false),
+        };
+
+        if wildcard && !matches!(operator, Operator::Equal | Operator::NotEqual) {
+            return Err(SpecifierParseError::UnexpectedWildcard(trimmed.to_string()));
+        }
+
+        let version = Version::parse(version)
+            .map_err(|e| SpecifierParseError::InvalidVersion(trimmed.to_string(), e))?;
+
+        if operator == Operator::Compatible && version.release.len() < 2 {
+            return Err(SpecifierParseError::InvalidCompatible(trimmed.to_string()));
+        }
+
+        Ok(Self {
+            operator,
+            version,
+            wildcard,
+        })
+    }
+
+    /// Whether the version matches the specifier.
+    ///
+    /// The local label of the version is ignored, except for `==` with a local version and `===`
+    pub fn contains(&self, version: &Version) -> bool {
+        let public = version.without_local();
+
+        match self.operator {
+            Operator::Equal => self.equals(version),
+            Operator::NotEqual => !self.equals(version),
+            Operator::Compatible => {
+                let prefix = &self.version.release[..self.version.release.len() - 1];
+                public >= self.version && self.has_prefix(&public, prefix)
+            }
+            Operator::LessEqual => public <= self.version,
+            Operator::GreaterEqual => public >= self.version,
+            // `<1.0` does not match `1.0a1`, unless the specifier itself is a pre-release
+            Operator::Less => {
+                public < self.version
+                    && (self.version.is_prerelease()
+                        || !public.is_prerelease()
+                        || !self.same_release(&public))
+            }
+            // `>1.0` does not match `1.0.post1`, unless the specifier itself is a post-release
+            Operator::Greater => {
+                public > self.version
+                    && (self.version.post.is_some()
+                        || public.post.is_none()
+                        || !self.same_release(&public))
+            }
+            // Only the normalized form of the version is known, so that is what gets compared
+            Operator::ArbitraryEqual => version.to_string() == self.version.to_string(),
+        }
+    }
+
+    fn equals(&self, version: &Version) -> bool {
+        if self.wildcard {
+            return self.has_prefix(&version.without_local(), &self.version.release);
+        }
+
+        if self.version.local.is_empty() {
+            version.without_local() == self.version
+        } else {
+            *version == self.version
+        }
+    }
+
+    /// Whether the release segment of the version starts with the prefix, padding the release
+    /// with zeros if it is shorter
+    fn has_prefix(&self, version: &Version, prefix: &[u64]) -> bool {
+        version.epoch == self.version.epoch
+            && prefix
+                .iter()
+                .enumerate()
+                .all(|(idx, n)| version.release.get(idx).copied().unwrap_or(0) == *n)
+    }
+
+    fn same_release(&self, version: &Version) -> bool {
+        version.epoch == self.version.epoch
+            && version.trimmed_release() == self.version.trimmed_release()
+    }
+}
+
+impl std::str::FromStr for VersionSpecifiers {
+    type Err = SpecifierParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl std::fmt::Display for VersionSpecifiers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let specifiers: Vec<String> = self.0.iter().map(|s| s.to_string()).collect();
+        write!(f, "{}", specifiers.join(","))
+    }
+}
+
+impl std::fmt::Display for VersionSpecifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operator = match self.operator {
+            Operator::Compatible => "~=",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::LessEqual => "<=",
+            Operator::GreaterEqual => ">=",
+            Operator::Less => "<",
+            Operator::Greater => ">",
+            Operator::ArbitraryEqual => "===",
+        };
+
+        write!(f, "{}{}", operator, self.version)?;
+        if self.wildcard {
+            write!(f, ".*")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for VersionSpecifiers {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        Self::parse(&raw).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(raw: &str) -> Version {
+        Version::parse(raw).unwrap()
+    }
+
+    fn matches(specifiers: &str, version: &str) -> bool {
+        VersionSpecifiers::parse(specifiers).unwrap().contains(&v(version))
+    }
+
+    #[test]
+    fn parse_normalizes_alternative_spellings() {
+        assert_eq!(v("1.0-alpha.1").to_string(), "1.0a1");
+        assert_eq!(v("1.0.preview2").to_string(), "1.0rc2");
+        assert_eq!(v("1.0c3").to_string(), "1.0rc3");
+        assert_eq!(v("1.0-1").to_string(), "1.0.post1");
+        assert_eq!(v("1.0.rev").to_string(), "1.0.post0");
+        assert_eq!(v("v1.0_dev").to_string(), "1.0.dev0");
+        assert_eq!(v("1!2.0+Ubuntu-1").to_string(), "1!2.0+ubuntu.1");
+    }
+
+    #[test]
+    fn parse_rejects_invalid_versions() {
+        assert_eq!(Version::parse(""), Err(VersionParseError::Empty));
+        assert_eq!(Version::parse("x!1.0"), Err(VersionParseError::InvalidEpoch));
+        assert_eq!(Version::parse("dev"), Err(VersionParseError::MissingRelease));
+        assert_eq!(
+            Version::parse("1.0foo"),
+            Err(VersionParseError::TrailingCharacters("foo".to_string()))
+        );
+    }
+
+    #[test]
+    fn trailing_zeros_are_ignored() {
+        assert_eq!(v("1.0"), v("1.0.0"));
+        assert!(v("1.0") < v("1.0.1"));
+    }
+
+    #[test]
+    fn epochs_sort_before_everything_else() {
+        assert!(v("2024.1") < v("1!0.1"));
+        assert!(v("1!1.0") < v("2!0.1"));
+    }
+
+    #[test]
+    fn pre_post_and_dev_releases_are_ordered() {
+        let ordered = [
+            "1.0.dev1",
+            "1.0a1.dev1",
+            "1.0a1",
+            "1.0a2",
+            "1.0b1",
+            "1.0rc1",
+            "1.0",
+            "1.0.post1.dev1",
+            "1.0.post1",
+            "1.1.dev1",
+        ];
+
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn local_versions_sort_after_the_public_version() {
+        assert!(v("1.0") < v("1.0+abc"));
+        assert!(v("1.0+abc") < v("1.0+abc.1"));
+        // Numeric segments sort after alphanumeric ones
+        assert!(v("1.0+abc") < v("1.0+1"));
+        assert!(v("1.0+2") < v("1.0+10"));
+        assert!(v("1.0+1") < v("1.0.post1"));
+    }
+
+    #[test]
+    fn empty_specifiers_match_everything() {
+        assert!(matches("", "1.0"));
+        assert!(matches("*", "1.0a1"));
+    }
+
+    #[test]
+    fn compatible_release() {
+        assert!(matches("~=2.12", "2.12"));
+        assert!(matches("~=2.12", "2.12.3"));
+        assert!(matches("~=2.12", "2.99"));
+        assert!(!matches("~=2.12", "2.11"));
+        assert!(!matches("~=2.12", "3.0"));
+        assert!(!matches("~=2.12", "1!2.12"));
+
+        assert!(matches("~=2.12.1", "2.12.5"));
+        assert!(!matches("~=2.12.1", "2.13"));
+
+        assert_eq!(
+            VersionSpecifier::parse("~=2"),
+            Err(SpecifierParseError::InvalidCompatible("~=2".to_string()))
+        );
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(!matches("!=1.*", "1.0"));
+        assert!(!matches("!=1.*", "1.5.2"));
+        assert!(!matches("!=1.*", "1.0a1"));
+        assert!(!matches("!=1.*", "1.2+local"));
+        assert!(matches("!=1.*", "2.0"));
+        assert!(matches("!=1.*", "0.9"));
+
+        assert!(matches("==1.2.*", "1.2"));
+        assert!(matches("==1.2.*", "1.2.7"));
+        assert!(!matches("==1.2.*", "1.20"));
+
+        assert_eq!(
+            VersionSpecifier::parse(">=1.*"),
+            Err(SpecifierParseError::UnexpectedWildcard(">=1.*".to_string()))
+        );
+    }
+
+    #[test]
+    fn less_than_excludes_prereleases_of_the_bound() {
+        assert!(matches("<2.15", "2.14"));
+        assert!(matches("<2.15", "2.14rc1"));
+        assert!(!matches("<2.15", "2.15rc1"));
+        assert!(!matches("<2.15", "2.15.dev0"));
+        assert!(!matches("<2.15", "2.15"));
+
+        assert!(matches("<2.15rc1", "2.15b1"));
+        assert!(!matches("<2.15rc1", "2.15rc1"));
+    }
+
+    #[test]
+    fn greater_than_excludes_post_releases_of_the_bound() {
+        assert!(!matches(">1.0", "1.0.post1"));
+        assert!(matches(">1.0", "1.1"));
+        assert!(matches(">1.0.post1", "1.0.post2"));
+    }
+
+    #[test]
+    fn local_versions_only_matter_if_specified() {
+        assert!(matches("==1.0", "1.0+local"));
+        assert!(matches("==1.0+local", "1.0+local"));
+        assert!(!matches("==1.0+local", "1.0"));
+        assert!(matches("<=1.0", "1.0+local"));
+        assert!(matches("===1.0+local", "1.0+local"));
+        assert!(!matches("===1.0", "1.0+local"));
+    }
+
+    #[test]
+    fn allowed_versions_are_a_union() {
+        let mut allowed = AllowedVersions::from(VersionSpecifiers::parse("<2.0").unwrap());
+        allowed.union(&VersionSpecifiers::parse(">=3.0").unwrap());
+
+        assert!(allowed.contains(&v("1.5")));
+        assert!(!allowed.contains(&v("2.5")));
+        assert!(allowed.contains(&v("3.1")));
+        assert!(!allowed.is_all());
+
+        allowed.union(&VersionSpecifiers::default());
+        assert!(allowed.is_all());
+        assert!(allowed.contains(&v("2.5")));
+    }
+
+    #[test]
+    fn all_specifiers_have_to_match() {
+        assert!(matches(">=2.12, <2.15", "2.14.1"));
+        assert!(!matches(">=2.12, <2.15", "2.15"));
+        assert!(!matches(">=2.12, !=2.13.*", "2.13.1"));
+        assert_eq!(
+            VersionSpecifiers::parse(">=2.12,<2.15").unwrap().to_string(),
+            ">=2.12,<2.15"
+        );
+    }
+}